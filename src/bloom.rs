@@ -1,8 +1,12 @@
-use std::collections::hash_map::RandomState;
-use std::hash::{BuildHasher, Hash, Hasher};
-use std::time::{Duration, Instant};
+use std::io::{self, Read};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::marker::PhantomData;
 
+use bitcoin::hashes::{siphash24, HashEngine};
+
+use rand::Rng;
+
 // Constants for roughly 1 in 1 million fp with 18m entries
 /// Number of entries in the filter (each 4 bits). 256MiB in total.
 const FILTER_SIZE: usize = 64 * 1024 * 1024 * 8;
@@ -15,39 +19,157 @@ const GENERATION_BITS: usize = 4;
 pub const GENERATION_COUNT: usize = (1 << GENERATION_BITS) - 1;
 const ELEMENTS_PER_VAR: usize = 64 / GENERATION_BITS;
 
+/// Saved filters start with this magic, followed by a u32 format version (all little-endian). The
+/// bits come last, after a byte which is 0 if they're all unset and left out.
+const SERIALIZED_MAGIC: &[u8; 8] = b"DNSSEEDB";
+const SERIALIZED_VERSION: u32 = 1;
+
+fn read_bytes<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u8; N]> {
+	let mut res = [0; N];
+	reader.read_exact(&mut res)?;
+	Ok(res)
+}
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+	Ok(u64::from_le_bytes(read_bytes(reader)?))
+}
+fn invalid(msg: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// SipHash-2-4 with explicit keys, which (unlike RandomState) we can save and restore.
+struct SipHasher(siphash24::HashEngine);
+impl Hasher for SipHasher {
+	fn finish(&self) -> u64 {
+		siphash24::Hash::from_engine_to_u64(self.0.clone())
+	}
+	fn write(&mut self, bytes: &[u8]) {
+		self.0.input(bytes);
+	}
+}
+
 pub struct RollingBloomFilter<T: Hash> {
 	last_roll: Instant,
 	inserted_in_last_generations: [usize; GENERATION_COUNT - 1],
 	inserted_since_last_roll: usize,
 	current_generation: u8,
+	/// Empty until the first insert, so that we don't allocate 256MiB for a new filter which is
+	/// about to be replaced by the saved one
 	bits: Vec<u64>,
-	hash_keys: [RandomState; HASHES],
+	hash_keys: [(u64, u64); HASHES],
 	_entry_type: PhantomData<T>,
 }
 
 impl<T: Hash> RollingBloomFilter<T> {
 	pub fn new() -> Self {
-		let mut bits = Vec::new();
-		bits.resize(FILTER_SIZE * GENERATION_BITS / 64, 0);
+		let mut rng = rand::thread_rng();
+		let mut hash_keys = [(0, 0); HASHES];
+		for keys in hash_keys.iter_mut() {
+			*keys = rng.gen();
+		}
 		Self {
 			last_roll: Instant::now(),
 			inserted_since_last_roll: 0,
 			inserted_in_last_generations: [0; GENERATION_COUNT - 1],
 			current_generation: 1,
-			bits,
-			hash_keys: [RandomState::new(), RandomState::new(), RandomState::new(), RandomState::new(), RandomState::new(),
-			            RandomState::new(), RandomState::new(), RandomState::new(), RandomState::new(), RandomState::new(),
-			            RandomState::new(), RandomState::new(), RandomState::new(), RandomState::new(), RandomState::new(),
-			            RandomState::new(), RandomState::new(), RandomState::new(), RandomState::new(), RandomState::new(),
-			            RandomState::new(), RandomState::new(), RandomState::new(), RandomState::new(), RandomState::new(),
-			            RandomState::new(), RandomState::new()],
+			bits: Vec::new(),
+			hash_keys,
 			_entry_type: PhantomData,
 		}
 	}
 
+	/// Serializes the whole filter, including its hash keys and when it last rolled, so that it
+	/// can be restored with deserialize.
+	pub fn serialize(&self) -> Vec<u8> {
+		let mut res = Vec::with_capacity(self.bits.len() * 8 + 1024);
+		res.extend_from_slice(SERIALIZED_MAGIC);
+		res.extend_from_slice(&SERIALIZED_VERSION.to_le_bytes());
+		res.push(GENERATION_BITS as u8);
+		res.extend_from_slice(&(FILTER_SIZE as u64).to_le_bytes());
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("time > 1970").as_secs();
+		res.extend_from_slice(&now.saturating_sub(self.last_roll.elapsed().as_secs()).to_le_bytes());
+		for count in self.inserted_in_last_generations.iter() {
+			res.extend_from_slice(&(*count as u64).to_le_bytes());
+		}
+		res.extend_from_slice(&(self.inserted_since_last_roll as u64).to_le_bytes());
+		res.push(self.current_generation);
+		for (k0, k1) in self.hash_keys.iter() {
+			res.extend_from_slice(&k0.to_le_bytes());
+			res.extend_from_slice(&k1.to_le_bytes());
+		}
+		if self.bits.is_empty() {
+			res.push(0);
+		} else if cfg!(target_endian = "little") {
+			res.push(1);
+			// This is usually done with the nodes lock held, so copy the bits in one go
+			res.extend_from_slice(unsafe { std::slice::from_raw_parts(self.bits.as_ptr() as *const u8, self.bits.len() * 8) });
+		} else {
+			res.push(1);
+			for var in self.bits.iter() {
+				res.extend_from_slice(&var.to_le_bytes());
+			}
+		}
+		res
+	}
+
+	/// Restores a filter saved with serialize, failing with InvalidData if it's corrupt or was
+	/// saved with different parameters. The bits are read straight into the filter, so that we
+	/// don't need room for two copies of them.
+	pub fn deserialize<R: Read>(reader: &mut R) -> io::Result<Self> {
+		if read_bytes::<_, 8>(reader)? != *SERIALIZED_MAGIC || read_bytes::<_, 4>(reader)? != SERIALIZED_VERSION.to_le_bytes() {
+			return Err(invalid("bad header"));
+		}
+		if read_bytes::<_, 1>(reader)?[0] as usize != GENERATION_BITS || read_u64(reader)? != FILTER_SIZE as u64 {
+			return Err(invalid("different filter parameters"));
+		}
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("time > 1970").as_secs();
+		let last_roll_age = Duration::from_secs(now.saturating_sub(read_u64(reader)?));
+		let last_roll = Instant::now().checked_sub(last_roll_age).unwrap_or_else(Instant::now);
+		let mut inserted_in_last_generations = [0; GENERATION_COUNT - 1];
+		for count in inserted_in_last_generations.iter_mut() {
+			*count = read_u64(reader)? as usize;
+		}
+		let inserted_since_last_roll = read_u64(reader)? as usize;
+		let current_generation = read_bytes::<_, 1>(reader)?[0];
+		if current_generation == 0 || current_generation as usize > GENERATION_COUNT { return Err(invalid("bad generation")); }
+		let mut hash_keys = [(0, 0); HASHES];
+		for keys in hash_keys.iter_mut() {
+			*keys = (read_u64(reader)?, read_u64(reader)?);
+		}
+		let bits = match read_bytes::<_, 1>(reader)?[0] {
+			0 => Vec::new(),
+			1 => {
+				let mut bits = vec![0u64; FILTER_SIZE * GENERATION_BITS / 64];
+				if cfg!(target_endian = "little") {
+					reader.read_exact(unsafe { std::slice::from_raw_parts_mut(bits.as_mut_ptr() as *mut u8, bits.len() * 8) })?;
+				} else {
+					for var in bits.iter_mut() {
+						*var = read_u64(reader)?;
+					}
+				}
+				bits
+			},
+			_ => return Err(invalid("bad bits flag")),
+		};
+		if reader.read(&mut [0])? != 0 { return Err(invalid("trailing data")); }
+		Ok(Self {
+			last_roll,
+			inserted_in_last_generations,
+			inserted_since_last_roll,
+			current_generation,
+			bits,
+			hash_keys,
+			_entry_type: PhantomData,
+		})
+	}
+
+	fn hasher(keys: &(u64, u64)) -> SipHasher {
+		SipHasher(siphash24::HashEngine::with_keys(keys.0, keys.1))
+	}
+
 	pub fn contains(&self, item: &T) -> bool {
-		for state in self.hash_keys.iter() {
-			let mut hasher = state.build_hasher();
+		if self.bits.is_empty() { return false; }
+		for keys in self.hash_keys.iter() {
+			let mut hasher = Self::hasher(keys);
 			item.hash(&mut hasher);
 			let idx = hasher.finish() as usize;
 
@@ -67,6 +189,9 @@ impl<T: Hash> RollingBloomFilter<T> {
 	}
 
 	pub fn insert(&mut self, item: &T, roll_duration: Duration) {
+		if self.bits.is_empty() {
+			self.bits = vec![0; FILTER_SIZE * GENERATION_BITS / 64];
+		}
 		if Instant::now() - self.last_roll > roll_duration / GENERATION_COUNT as u32 ||
 		   self.inserted_since_last_roll > ROLL_COUNT {
 			self.current_generation += 1;
@@ -93,8 +218,8 @@ impl<T: Hash> RollingBloomFilter<T> {
 			self.inserted_since_last_roll = 0;
 		}
 
-		for state in self.hash_keys.iter() {
-			let mut hasher = state.build_hasher();
+		for keys in self.hash_keys.iter() {
+			let mut hasher = Self::hasher(keys);
 			item.hash(&mut hasher);
 			let idx = hasher.finish() as usize;
 
@@ -110,6 +235,12 @@ impl<T: Hash> RollingBloomFilter<T> {
 #[test]
 fn test_bloom() {
 	let mut filter = RollingBloomFilter::new();
+	assert!(!filter.contains(&0));
+	// An unused filter is saved without its bits, and stays unallocated when restored
+	let data = filter.serialize();
+	assert!(data.len() < 1024);
+	let empty: RollingBloomFilter<i32> = RollingBloomFilter::deserialize(&mut &data[..]).unwrap();
+	assert!(!empty.contains(&0) && empty.bits.is_empty());
 	for i in 0..1000 {
 		filter.insert(&i, Duration::from_secs(60 * 60 * 24));
 	}
@@ -143,4 +274,22 @@ fn test_bloom() {
 	for i in 1000..2001 {
 		assert!(filter.contains(&i));
 	}
+
+	let data = filter.serialize();
+	let restored: RollingBloomFilter<i32> = RollingBloomFilter::deserialize(&mut &data[..]).unwrap();
+	assert_eq!(restored.get_element_count(), filter.get_element_count());
+	assert_eq!(restored.current_generation, filter.current_generation);
+	for i in 0..1000 {
+		assert!(!restored.contains(&i));
+	}
+	for i in 1000..2001 {
+		assert!(restored.contains(&i));
+	}
+	assert!(RollingBloomFilter::<i32>::deserialize(&mut &data[..data.len() - 1]).is_err());
+	let mut trailing = data.clone();
+	trailing.push(0);
+	assert!(RollingBloomFilter::<i32>::deserialize(&mut &trailing[..]).is_err());
+	let mut wrong_params = data;
+	wrong_params[12] = GENERATION_BITS as u8 + 1;
+	assert_eq!(RollingBloomFilter::<i32>::deserialize(&mut &wrong_params[..]).err().unwrap().kind(), io::ErrorKind::InvalidData);
 }
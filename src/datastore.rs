@@ -1,27 +1,94 @@
 use std::cmp;
 use std::convert::TryInto;
-use std::collections::{HashSet, HashMap, hash_map};
-use std::sync::{Arc, RwLock};
+use std::collections::{BTreeMap, BTreeSet, HashSet, HashMap, hash_map};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::io::{BufRead, BufReader};
+use std::fmt;
+use std::str::FromStr;
 
-use bitcoin::network::address::{Address, AddrV2Message};
+use bitcoin::network::address::{Address, AddrV2, AddrV2Message};
+use bitcoin::network::constants::ServiceFlags;
 
-use rand::thread_rng;
-use rand::seq::{SliceRandom, IteratorRandom};
+use bitcoin::hashes::{sha256, Hash};
+
+use sha3::{Digest, Sha3_256};
+
+use rand::{thread_rng, Rng};
+use rand::seq::SliceRandom;
 
 use tokio::prelude::*;
 use tokio::fs::File;
-use tokio::io::write_all;
+use tokio::io::{read_to_end, write_all};
 
 use regex::Regex;
 
 use crate::bloom::RollingBloomFilter;
 use crate::bgp_client::BGPClient;
+use crate::network::NetworkParams;
+use crate::zone::{sub_label, srv_target_label, type_to_str, RecordData, ZoneConfig};
+
+use crate::START_SHUTDOWN;
+
+/// The service bits a client gets if they query the bare seed name.
+pub const DEFAULT_DNS_SERVICES: u64 = 1;
+/// By default clients may require any combination of NETWORK, BLOOM, WITNESS, COMPACT_FILTERS,
+/// NETWORK_LIMITED and P2P_V2 in x<hex> names.
+const DEFAULT_ALLOWED_DNS_SERVICES: u64 = 0b110001001101;
+/// The service bit combinations the zone dump has always had, which we keep publishing (as far as
+/// they're allowed) on top of the combinations from zone_service_masks.
+const DNS_SERVICE_MASKS: [u64; 17] = [
+	0b00000000001,
+	0b00000000100,
+	0b00000000101,
+	0b00000001000,
+	0b00000001001,
+	0b00000001100,
+	0b00000001101,
+	0b00001001001,
+	0b10000000000,
+	0b10000000001,
+	0b10000000100,
+	0b10000000101,
+	0b10000001000,
+	0b10000001001,
+	0b10000001100,
+	0b10000001101,
+	0b10001001000,
+//    ^ NODE_NETWORK_LIIMTED
+//COMPACT_FILTERS ^   ^ NODE_BLOOM
+//      NODE_WITNESS ^  ^ NODE_NETWORK
+// We support all combos of NETWORK, NETWORK_LIMITED, BLOOM, and WITNESS
+// We support COMPACT_FILTERS with WITNESS and NETWORK or NETWORK_LIIMTED.
+];
+/// The most allowed service bits (the lowest ones) we combine in the zone dump, so that allowing
+/// lots of bits doesn't blow up the zone.
+const MAX_ZONE_SERVICE_BITS: usize = 8;
 
-pub const SECS_PER_SCAN_RESULTS: u64 = 15;
-const MAX_CONNS_PER_SEC_PER_STATUS: u64 = 1000;
+/// Gets the service bit combinations we write to the zone dump, as x<hex>.seed: DNS_SERVICE_MASKS
+/// and every combination of the allowed bits which includes NETWORK or NETWORK_LIMITED, all
+/// limited to the allowed bits, in ascending order. The built-in DNS server resolves any
+/// combination of the allowed bits at query time instead.
+pub fn zone_service_masks(allowed_services: u64) -> Vec<u64> {
+	let mut bits = allowed_services;
+	let mut allowed = 0;
+	for _ in 0..MAX_ZONE_SERVICE_BITS {
+		if bits == 0 { break; }
+		let lowest_bit = bits & bits.wrapping_neg();
+		allowed |= lowest_bit;
+		bits &= !lowest_bit;
+	}
+	let full_node = ServiceFlags::NETWORK.as_u64() | ServiceFlags::NETWORK_LIMITED.as_u64();
+	let mut res: BTreeSet<u64> = DNS_SERVICE_MASKS.iter().map(|mask| mask & allowed_services).filter(|mask| *mask != 0).collect();
+	let mut mask = allowed;
+	while mask != 0 {
+		if mask & full_node != 0 { res.insert(mask); }
+		mask = (mask - 1) & allowed;
+	}
+	res.into_iter().collect()
+}
 
 #[derive(Clone, Copy, Hash, PartialEq, Eq)]
 pub enum AddressState {
@@ -109,12 +176,100 @@ impl AddressState {
 	}
 }
 
-#[derive(Hash, PartialEq, Eq)]
+#[derive(Clone, Copy, Hash, PartialEq, Eq)]
 pub enum U64Setting {
 	RunTimeout,
 	WasGoodTimeout,
 	RescanInterval(AddressState),
+	/// The longest we'll back off rescanning a node in the given state which keeps failing
+	MaxRescanInterval(AddressState),
 	MinProtocolVersion,
+	/// The service bits clients may require via x<hex> DNS names.
+	AllowedDnsServices,
+	/// Nodes which haven't been Good or responded to us for this many seconds are evicted (0 for
+	/// never)
+	NodeMaxAge,
+	/// If we know of more nodes than this, those which were last Good or responded the longest
+	/// ago are evicted (0 for no limit). Good and WasGood nodes are never evicted.
+	MaxNodes,
+	/// The most connections we'll have open (or waiting to open) at once, on any network (0 for
+	/// no limit)
+	MaxConnections,
+	MaxIPv4Connections,
+	MaxIPv6Connections,
+	/// The most connections we'll make through the Tor proxy at once (ie to Tor v2 and v3 nodes)
+	MaxTorConnections,
+	/// The most connections we'll make to any one IPv4 /24 or IPv6 /48 in a scan round (0 for no
+	/// limit)
+	MaxSubnetScans,
+	/// The most connections we'll make to any one ASN in a scan round (0 for no limit)
+	MaxAsnScans,
+	/// How often (in seconds) we start a round of scans
+	ScanRoundSecs,
+	/// The most nodes in each state we'll scan per second of a scan round
+	MaxScansPerSecPerState,
+	/// How far below our tip the block we ask nodes for is. Pruned nodes only serve the last 288
+	/// blocks, so it can't be any deeper.
+	RequestBlockDepth,
+	/// How far above the requested block a node's start height may be before it's HighBlockCount
+	MaxHeightAhead,
+}
+
+impl U64Setting {
+	/// Every setting, in the order they're written to the settings file
+	fn all() -> Vec<U64Setting> {
+		let mut res = vec![U64Setting::RunTimeout, U64Setting::WasGoodTimeout, U64Setting::MinProtocolVersion];
+		res.extend((0..AddressState::get_count()).map(|i| U64Setting::RescanInterval(AddressState::from_num(i).unwrap())));
+		res.push(U64Setting::AllowedDnsServices);
+		res.push(U64Setting::NodeMaxAge);
+		res.push(U64Setting::MaxNodes);
+		res.extend((0..AddressState::get_count()).map(|i| U64Setting::MaxRescanInterval(AddressState::from_num(i).unwrap())));
+		res.extend_from_slice(&[U64Setting::MaxConnections, U64Setting::MaxIPv4Connections, U64Setting::MaxIPv6Connections, U64Setting::MaxTorConnections,
+			U64Setting::MaxSubnetScans, U64Setting::MaxAsnScans, U64Setting::ScanRoundSecs, U64Setting::MaxScansPerSecPerState,
+			U64Setting::RequestBlockDepth, U64Setting::MaxHeightAhead]);
+		res
+	}
+
+	/// Checks that value makes sense for the setting, returning why not if it doesn't
+	pub fn validate(&self, value: u64) -> Result<(), String> {
+		let (min, max) = match self {
+			// A zero timeout would give up on every connection before it's even made
+			U64Setting::RunTimeout => (1, u64::MAX),
+			U64Setting::WasGoodTimeout => (0, u32::MAX as u64),
+			U64Setting::ScanRoundSecs => (1, 3600),
+			U64Setting::MaxScansPerSecPerState => (1, u32::MAX as u64),
+			U64Setting::RequestBlockDepth => (1, 288),
+			U64Setting::MaxHeightAhead => (0, u32::MAX as u64),
+			_ => (0, u64::MAX),
+		};
+		if value < min || value > max {
+			Err(format!("{} must be between {} and {}", self.key(), min, max))
+		} else { Ok(()) }
+	}
+
+	/// The setting's key in the settings file, eg rescan_interval.low_block_count
+	fn key(&self) -> String {
+		match self {
+			U64Setting::RunTimeout => "run_timeout".to_string(),
+			U64Setting::WasGoodTimeout => "was_good_timeout".to_string(),
+			U64Setting::RescanInterval(state) => "rescan_interval.".to_string() + &state.to_str().to_ascii_lowercase().replace(' ', "_"),
+			U64Setting::MaxRescanInterval(state) => "max_rescan_interval.".to_string() + &state.to_str().to_ascii_lowercase().replace(' ', "_"),
+			U64Setting::MinProtocolVersion => "min_protocol_version".to_string(),
+			U64Setting::AllowedDnsServices => "allowed_dns_services".to_string(),
+			U64Setting::NodeMaxAge => "node_max_age".to_string(),
+			U64Setting::MaxNodes => "max_nodes".to_string(),
+			U64Setting::MaxConnections => "max_connections".to_string(),
+			U64Setting::MaxIPv4Connections => "max_connections.ipv4".to_string(),
+			U64Setting::MaxIPv6Connections => "max_connections.ipv6".to_string(),
+			U64Setting::MaxTorConnections => "max_connections.tor".to_string(),
+			U64Setting::MaxSubnetScans => "max_scans_per_round.subnet".to_string(),
+			U64Setting::MaxAsnScans => "max_scans_per_round.asn".to_string(),
+			U64Setting::ScanRoundSecs => "scan_round_secs".to_string(),
+			U64Setting::MaxScansPerSecPerState => "max_scans_per_sec_per_state".to_string(),
+			U64Setting::RequestBlockDepth => "request_block_depth".to_string(),
+			U64Setting::MaxHeightAhead => "max_height_ahead".to_string(),
+		}
+	}
 }
 
 #[derive(Hash, PartialEq, Eq)]
@@ -122,14 +277,176 @@ pub enum RegexSetting {
 	SubverRegex,
 }
 
+/// Counts of the addresses an upstream DNS seed has given us since startup.
+#[derive(Clone, Default)]
+pub struct DnsSeedStats {
+	/// Addresses returned across all queries
+	pub returned: u64,
+	/// Addresses we didn't already know of
+	pub new: u64,
+	/// New addresses which we have since found to be Good
+	pub good: u64,
+}
+
+/// The windows (in seconds) over which we track exponentially-decayed uptime: 2 hours, 8 hours, a
+/// day, a week and a month.
+pub const RELIABILITY_WINDOWS: [u32; 5] = [2 * 60 * 60, 8 * 60 * 60, 24 * 60 * 60, 7 * 24 * 60 * 60, 30 * 24 * 60 * 60];
+/// The (uptime, decayed scan count) a node must exceed in any one window to be considered
+/// reliable, as in sipa's seeder.
+const RELIABILITY_THRESHOLDS: [(f32, f32); 5] = [(0.85, 2.0), (0.70, 4.0), (0.55, 8.0), (0.45, 16.0), (0.35, 32.0)];
+
+#[derive(Clone, Copy, Default)]
+struct ReliabilityStat {
+	weight: f32,
+	count: f32,
+	reliability: f32,
+}
+
+/// A node's uptime over each of RELIABILITY_WINDOWS, which is only tracked once it has been Good
+/// (until then it's 0 in every window anyway).
+#[derive(Clone, Copy, Default)]
+pub struct Reliability([ReliabilityStat; 5]);
+impl Reliability {
+	/// Records a scan result, age seconds after the previous one.
+	fn update(&mut self, good: bool, age: u32) {
+		for (stat, window) in self.0.iter_mut().zip(RELIABILITY_WINDOWS.iter()) {
+			let f = (-(age as f64) / *window as f64).exp() as f32;
+			stat.reliability = stat.reliability * f + if good { 1.0 - f } else { 0.0 };
+			stat.count = stat.count * f + 1.0;
+			stat.weight = stat.weight * f + (1.0 - f);
+		}
+	}
+
+	/// Gets the fraction of scans in the given window (as an index into RELIABILITY_WINDOWS) in
+	/// which the node was Good. This is normalised by the weight, as in sipa's seeder, so that a
+	/// node which was only found Good recently isn't treated as having been down before that.
+	pub fn uptime(&self, window: usize) -> f32 {
+		let stat = &self.0[window];
+		if stat.weight > 0.0 { stat.reliability / stat.weight } else { 0.0 }
+	}
+
+	/// Whether the node meets sipa's reliability thresholds in the given window, which requires
+	/// enough scans in it as well as enough uptime.
+	pub fn is_reliable_in(&self, window: usize) -> bool {
+		let (min_uptime, min_count) = RELIABILITY_THRESHOLDS[window];
+		self.uptime(window) > min_uptime && self.0[window].count > min_count
+	}
+
+	/// Whether the node has been consistently Good in any window. Reliable nodes are preferred in
+	/// DNS responses.
+	pub fn is_reliable(&self) -> bool {
+		(0..RELIABILITY_WINDOWS.len()).any(|window| self.is_reliable_in(window))
+	}
+}
+
+/// How long we reuse the DnsCandidates for a service mask before looking at every node again
+const DNS_CANDIDATES_REFRESH: Duration = Duration::from_secs(5);
+/// The most candidates we'll look at (and look up the ASN of) for one network in one DNS response
+const MAX_DNS_CANDIDATES_WALKED: usize = 1000;
+
+/// The DnsCandidates for each (services, any_port), with when they were last refreshed
+type DnsCandidatesCache = HashMap<(u64, bool), (Instant, Arc<DnsCandidates>)>;
+
+/// The Good nodes with some service mask, by network, each split into (reliable, others) and
+/// shuffled. These are cached, so that each DNS query only has to sample from them.
+#[derive(Default)]
+struct DnsCandidates([(Vec<SocketAddr>, Vec<SocketAddr>); NetworkType::get_count() as usize]);
+impl DnsCandidates {
+	fn new(nodes: Vec<(NetworkType, SocketAddr, bool)>) -> DnsCandidates {
+		let mut res = DnsCandidates::default();
+		for (network, addr, reliable) in nodes {
+			let (reliable_set, other_set) = &mut res.0[network.to_num() as usize];
+			if reliable { reliable_set.push(addr); } else { other_set.push(addr); }
+		}
+		let mut rng = thread_rng();
+		for (reliable_set, other_set) in res.0.iter_mut() {
+			reliable_set.shuffle(&mut rng);
+			other_set.shuffle(&mut rng);
+		}
+		res
+	}
+
+	/// Walks (up to MAX_DNS_CANDIDATES_WALKED of) the candidates on the given network, reliable
+	/// ones first, each from a random starting point so that every response gets a different
+	/// selection.
+	fn sample(&self, network: NetworkType) -> impl Iterator<Item=SocketAddr> + '_ {
+		fn rotated(set: &[SocketAddr]) -> impl Iterator<Item=&SocketAddr> {
+			let start = if set.is_empty() { 0 } else { thread_rng().gen_range(0..set.len()) };
+			set[start..].iter().chain(set[..start].iter())
+		}
+		let (reliable_set, other_set) = &self.0[network.to_num() as usize];
+		rotated(reliable_set).chain(rotated(other_set)).cloned().take(MAX_DNS_CANDIDATES_WALKED)
+	}
+}
+
+#[test]
+fn reliability_test() {
+	let mut rel = Reliability::default();
+	assert!(!rel.is_reliable());
+	// The first scan sets each window's uptime outright
+	rel.update(true, u32::MAX);
+	assert_eq!(rel.uptime(0), 1.0);
+	assert!(!rel.is_reliable());
+	// After a few hours of scans every 15 minutes the node is reliable over 2 hours...
+	for _ in 0..12 { rel.update(true, 15 * 60); }
+	assert!(rel.is_reliable_in(0) && !rel.is_reliable_in(4));
+	assert!(rel.is_reliable());
+	// ...but a node which flaps every other scan only meets the (looser) week and month thresholds
+	for i in 0..2000 { rel.update(i % 2 == 0, 15 * 60); }
+	assert!((rel.uptime(0) - 0.5).abs() < 0.1);
+	assert!(!rel.is_reliable_in(0) && !rel.is_reliable_in(1) && !rel.is_reliable_in(2));
+	assert!(rel.is_reliable_in(3) && rel.is_reliable_in(4));
+	for _ in 0..8 { rel.update(false, 15 * 60); }
+	assert!(rel.uptime(0) < 0.2 && rel.uptime(4) > 0.4);
+
+	// A node first found Good 15 minutes after we first tried it has only been up since then
+	let mut rel = Reliability::default();
+	rel.update(true, 15 * 60);
+	assert!((rel.uptime(4) - 1.0).abs() < 0.001);
+	rel.update(false, 15 * 60);
+	assert!((rel.uptime(4) - 0.5).abs() < 0.001);
+
+	let addr = |a: &str| a.parse::<SocketAddr>().unwrap();
+	let candidates = DnsCandidates::new(vec![(NetworkType::IPv4, addr("1.0.0.1:8333"), false), (NetworkType::IPv4, addr("1.0.0.2:8333"), true),
+		(NetworkType::IPv4, addr("1.0.0.3:8333"), false), (NetworkType::IPv4, addr("1.0.0.4:8333"), true),
+		(NetworkType::IPv6, addr("[2001:db8::1]:8333"), true)]);
+	let nodes: Vec<SocketAddr> = candidates.sample(NetworkType::IPv4).collect();
+	assert_eq!(nodes.len(), 4);
+	assert!(nodes[..2].contains(&addr("1.0.0.2:8333")) && nodes[..2].contains(&addr("1.0.0.4:8333")));
+	assert!(nodes[2..].contains(&addr("1.0.0.1:8333")) && nodes[2..].contains(&addr("1.0.0.3:8333")));
+	assert_eq!(candidates.sample(NetworkType::IPv6).count(), 1);
+	assert_eq!(candidates.sample(NetworkType::Cjdns).count(), 0);
+}
+
+/// Gets the current time in UNIX seconds, which fits in a u32 until 2106.
+pub fn unix_time() -> u32 {
+	SystemTime::now().duration_since(UNIX_EPOCH).expect("time > 1970").as_secs() as u32
+}
+
 struct Node {
-	// Times in seconds-since-startup
+	// Times in UNIX seconds (so that they survive restarts), or 0 for never
 	last_good: u32, // Ignored unless state is Good or WasGood
+	/// When we last got a response (of any kind) from the node
+	last_seen: u32,
+	/// When we first heard of the node (or migrated it from a nodes file without this)
+	first_seen: u32,
+	/// When we last finished a scan of the node, successfully or not
+	last_attempt: u32,
 	// Since everything is is 4-byte aligned, using a u64 for services blows up our size
 	// substantially. Instead, use a u32 pair and bit shift as needed.
 	last_services: (u32, u32),
 	state: AddressState,
-	queued: bool,
+	/// When the node is next due to be scanned (in Nodes::scan_queue), or 0 if it isn't queued
+	/// as we're scanning it now
+	next_scan: u32,
+	/// 1 + the index in Store::dns_seed_stats of the upstream DNS seed which first gave us this
+	/// node, until it is first Good (after which, or if it came from elsewhere, 0).
+	dns_seed: u8,
+	/// The number of scans in a row which didn't find the node Good
+	failures: u8,
+	/// The outcomes of our last HISTORY_LEN scans as 4-bit AddressStates, most recent in the low
+	/// bits, with unused slots set to 0xf.
+	history: u32,
 }
 impl Node {
 	#[inline]
@@ -144,21 +461,223 @@ impl Node {
 			((inp & 0x00000000ffffffff)      ) as u32
 		)
 	}
+	fn push_history(&mut self, state: AddressState) {
+		self.history = (self.history << 4) | state.to_num() as u32;
+	}
+	/// Gets the outcomes of our recent scans, most recent first.
+	fn history(&self) -> Vec<AddressState> {
+		(0..HISTORY_LEN).filter_map(|i| AddressState::from_num(((self.history >> (i * 4)) & 0xf) as u8)).collect()
+	}
+}
+
+/// The number of scan outcomes we remember per node
+const HISTORY_LEN: usize = 8;
+const EMPTY_HISTORY: u32 = u32::MAX;
+
+/// What a node told us about itself in its version message
+#[derive(Clone)]
+pub struct VersionInfo {
+	pub user_agent: String,
+	pub protocol_version: u32,
+	pub services: u64,
+	pub start_height: i32,
+}
+
+/// A snapshot of a node's record, for the console
+pub struct NodeInfo {
+	pub state: AddressState,
+	/// The services the node had when it was last Good
+	pub services: u64,
+	pub first_seen: u32,
+	pub last_good: u32,
+	pub last_seen: u32,
+	pub last_attempt: u32,
+	/// The number of scans in a row which didn't find the node Good
+	pub failures: u8,
+	pub reliability: Option<Reliability>,
+	/// Most recent first
+	pub history: Vec<AddressState>,
+	pub details: Option<NodeDetails>,
+}
+
+/// Details from the most recent scan of a node which got far enough to learn something
+#[derive(Clone)]
+pub struct NodeDetails {
+	/// The latest version message we got, which may be from an earlier scan than the rest
+	pub version: Option<VersionInfo>,
+	/// When we got the version message (UNIX seconds)
+	pub version_time: u32,
+	pub rtt_ms: Option<u32>,
+	/// The message printed for the latest scan, eg why it failed
+	pub last_message: String,
 }
 
 #[test]
 fn services_test() {
 	assert_eq!(
-		Node { last_good: 0, state: AddressState::Good, queued: false, last_services: Node::services(0x1badcafedeadbeef) }
+		Node { last_good: 0, last_seen: 0, first_seen: 0, last_attempt: 0, state: AddressState::Good, next_scan: 0, dns_seed: 0, failures: 0, history: EMPTY_HISTORY, last_services: Node::services(0x1badcafedeadbeef) }
 			.last_services(),
 		0x1badcafedeadbeef);
 }
 
-/// Essentially SocketAddr but without a traffic class or scope
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum NetworkType {
+	IPv4,
+	IPv6,
+	TorV2,
+	TorV3,
+	I2P,
+	Cjdns,
+}
+
+impl NetworkType {
+	pub fn from_num(num: u8) -> Option<NetworkType> {
+		match num {
+			0x0 => Some(NetworkType::IPv4),
+			0x1 => Some(NetworkType::IPv6),
+			0x2 => Some(NetworkType::TorV2),
+			0x3 => Some(NetworkType::TorV3),
+			0x4 => Some(NetworkType::I2P),
+			0x5 => Some(NetworkType::Cjdns),
+			_   => None,
+		}
+	}
+
+	pub fn to_num(&self) -> u8 {
+		match *self {
+			NetworkType::IPv4 => 0,
+			NetworkType::IPv6 => 1,
+			NetworkType::TorV2 => 2,
+			NetworkType::TorV3 => 3,
+			NetworkType::I2P => 4,
+			NetworkType::Cjdns => 5,
+		}
+	}
+
+	pub fn to_str(&self) -> &'static str {
+		match *self {
+			NetworkType::IPv4 => "IPv4",
+			NetworkType::IPv6 => "IPv6",
+			NetworkType::TorV2 => "Tor v2",
+			NetworkType::TorV3 => "Tor v3",
+			NetworkType::I2P => "I2P",
+			NetworkType::Cjdns => "CJDNS",
+		}
+	}
+
+	pub const fn get_count() -> u8 {
+		6
+	}
+}
+
+// base32 encoder/decoder and tests stolen (transliterated) from Bitcoin Core
+// Copyright (c) 2012-2019 The Bitcoin Core developers
+// Distributed under the MIT software license, see
+// http://www.opensource.org/licenses/mit-license.php.
+fn encode_base32(inp: &[u8]) -> String {
+	let mut ret = String::with_capacity(((inp.len() + 4) / 5) * 8);
+
+	let alphabet = "abcdefghijklmnopqrstuvwxyz234567";
+	let mut acc: u16 = 0;
+	let mut bits: u8 = 0;
+	for i in inp {
+		acc = ((acc << 8) | *i as u16) & ((1 << (8 + 5 - 1)) - 1);
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			let idx = ((acc >> bits) & ((1 << 5) - 1)) as usize;
+			ret += &alphabet[idx..idx + 1];
+		}
+	}
+	if bits != 0 {
+		let idx = ((acc << (5 - bits)) & ((1 << 5) - 1)) as usize;
+		ret += &alphabet[idx..idx + 1];
+	}
+	while ret.len() % 8 != 0 { ret += "=" };
+	return ret;
+}
+
+#[test]
+fn test_encode_base32() {
+	let tests_in = ["","f","fo","foo","foob","fooba","foobar"];
+	let tests_out = ["","my======","mzxq====","mzxw6===","mzxw6yq=","mzxw6ytb","mzxw6ytboi======"];
+	for (inp, out) in tests_in.iter().zip(tests_out.iter()) {
+		assert_eq!(&encode_base32(inp.as_bytes()), out);
+	}
+	// My seednode's onion addr:
+	assert_eq!(&encode_base32(&[0x6a, 0x8b, 0xd2, 0x78, 0x3f, 0x7a, 0xf8, 0x92, 0x8f, 0x80]), "nkf5e6b7pl4jfd4a");
+}
+
+fn decode_base32(inp: &[u8]) -> Option<Vec<u8>> {
+	let decode32_table: [i8; 256] = [
+		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, 26, 27, 28, 29, 30, 31, -1, -1, -1, -1,
+		-1, -1, -1, -1, -1,  0,  1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14,
+		15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, -1, -1, -1, -1, -1, -1,  0,  1,  2,
+		 3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22,
+		23, 24, 25, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1
+	];
+
+	let mut ret = Vec::with_capacity((inp.len() * 5) / 8);
+
+	let mut acc: u16 = 0;
+	let mut bits: u8 = 0;
+	for i in inp {
+		if *i == '=' as u8 { break; }
+		let codepoint = decode32_table[*i as usize];
+		if codepoint < 0 { return None; }
+		acc = ((acc << 5) | codepoint as u16) & ((1 << (8 + 5 - 1)) - 1);
+		bits += 5;
+		while bits >= 8 {
+			bits -= 8;
+			ret.push((acc >> bits) as u8);
+		}
+	}
+	Some(ret)
+}
+
+#[test]
+fn test_decode_base32() {
+	let tests_in = ["","f","fo","foo","foob","fooba","foobar"];
+	let tests_out = ["","my======","mzxq====","mzxw6===","mzxw6yq=","mzxw6ytb","mzxw6ytboi======"];
+	for (inp, out) in tests_in.iter().zip(tests_out.iter()) {
+		assert_eq!(&decode_base32(out.as_bytes()).unwrap()[..], inp.as_bytes());
+	}
+	// My seednode's onion addr:
+	assert_eq!(decode_base32("nkf5e6b7pl4jfd4a".as_bytes()).unwrap()[..],[0x6a, 0x8b, 0xd2, 0x78, 0x3f, 0x7a, 0xf8, 0x92, 0x8f, 0x80]);
+}
+
+/// The OnionCat prefix which Tor v2 addresses are stuffed into IPv6 addresses with
+const ONIONCAT_PREFIX: [u8; 6] = [0xFD,0x87,0xD8,0x7E,0xEB,0x43];
+/// The first byte of every CJDNS address (ie they all live in fc00::/8)
+const CJDNS_PREFIX: u8 = 0xFC;
+
+/// The two checksum bytes in a Tor v3 onion hostname (see Tor's rend-spec-v3.txt)
+fn torv3_checksum(pubkey: &[u8; 32]) -> [u8; 2] {
+	let mut hasher = Sha3_256::new();
+	hasher.update(b".onion checksum");
+	hasher.update(&pubkey[..]);
+	hasher.update(&[3u8]);
+	let hash = hasher.finalize();
+	[hash[0], hash[1]]
+}
+
+/// Essentially SocketAddr but without a traffic class or scope, and with Tor v3 onion services and
+/// I2P destination hashes (which don't fit in an IPv6 address). Tor v2 onions are stored
+/// OnionCat-encoded in V6, and CJDNS addresses (which all live in fc00::/8) as-is in V6.
 #[derive(Clone, PartialEq, Eq, Hash)]
-enum SockAddr {
+pub enum SockAddr {
 	V4(SocketAddrV4),
 	V6(([u16; 8], u16)),
+	TorV3(([u8; 32], u16)),
+	I2P(([u8; 32], u16)),
 }
 #[inline]
 fn segs_to_ip6(segs: &[u16; 8]) -> Ipv6Addr {
@@ -172,208 +691,1239 @@ impl From<SocketAddr> for SockAddr {
 		}
 	}
 }
-impl Into<SocketAddr> for &SockAddr {
-	fn into(self) -> SocketAddr {
+impl fmt::Display for SockAddr {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match self {
-			&SockAddr::V4(sa) => SocketAddr::V4(sa),
-			&SockAddr::V6(sa) => SocketAddr::V6(SocketAddrV6::new(segs_to_ip6(&sa.0), sa.1, 0, 0))
+			SockAddr::V4(sa) => write!(f, "{}", sa),
+			SockAddr::V6((ip, port)) => write!(f, "{}", SocketAddrV6::new(segs_to_ip6(ip), *port, 0, 0)),
+			SockAddr::TorV3((_, port)) => write!(f, "{}:{}", self.onion_hostname().unwrap(), port),
+			SockAddr::I2P((_, port)) => write!(f, "{}:{}", self.i2p_hostname().unwrap(), port),
 		}
 	}
 }
-impl ToString for SockAddr {
-	fn to_string(&self) -> String {
-		let sa: SocketAddr = self.into();
-		sa.to_string()
+impl FromStr for SockAddr {
+	type Err = ();
+	/// Parses an IP:port, or an onion (v2 or v3) or .b32.i2p address with a port.
+	fn from_str(s: &str) -> Result<SockAddr, ()> {
+		if let Some((host, port)) = s.rsplit_once(':') {
+			if let Some(i2p) = host.strip_suffix(".b32.i2p") {
+				let port = port.parse::<u16>().map_err(|_| ())?;
+				let decoded = decode_base32(i2p.as_bytes()).ok_or(())?;
+				if i2p.len() != 52 || decoded.len() != 32 { return Err(()); }
+				return Ok(SockAddr::I2P((decoded[..].try_into().unwrap(), port)));
+			}
+			if let Some(onion) = host.strip_suffix(".onion") {
+				let port = port.parse::<u16>().map_err(|_| ())?;
+				let decoded = decode_base32(onion.as_bytes()).ok_or(())?;
+				if onion.len() == 16 && decoded.len() == 10 {
+					let mut octets = [0; 16];
+					octets[..6].copy_from_slice(&ONIONCAT_PREFIX);
+					octets[6..].copy_from_slice(&decoded);
+					return Ok(SockAddr::V6((Ipv6Addr::from(octets).segments(), port)));
+				} else if onion.len() == 56 && decoded.len() == 35 && decoded[34] == 3 {
+					let pubkey: [u8; 32] = decoded[..32].try_into().unwrap();
+					if decoded[32..34] != torv3_checksum(&pubkey) { return Err(()); }
+					return Ok(SockAddr::TorV3((pubkey, port)));
+				}
+				return Err(());
+			}
+		}
+		s.parse::<SocketAddr>().map(SockAddr::from).map_err(|_| ())
 	}
 }
 impl SockAddr {
+	/// Converts an address from an addr message. Tor v2 addresses are stored OnionCat-encoded.
+	pub fn from_addr(addr: &Address) -> SockAddr {
+		match addr.socket_addr() {
+			Ok(socketaddr) => socketaddr.into(),
+			Err(_) => SockAddr::V6((addr.address, addr.port)),
+		}
+	}
+	/// Converts an address from an addrv2 message, if its network is one we know how to reach.
+	pub fn from_addrv2(addr: &AddrV2Message) -> Option<SockAddr> {
+		match addr.addr {
+			AddrV2::Ipv4(ip) => Some(SockAddr::V4(SocketAddrV4::new(ip, addr.port))),
+			AddrV2::Ipv6(ip) => Some(SockAddr::V6((ip.segments(), addr.port))),
+			AddrV2::TorV2(id) => {
+				let mut octets = [0; 16];
+				octets[..6].copy_from_slice(&ONIONCAT_PREFIX);
+				octets[6..].copy_from_slice(&id);
+				Some(SockAddr::V6((Ipv6Addr::from(octets).segments(), addr.port)))
+			},
+			AddrV2::TorV3(pubkey) => Some(SockAddr::TorV3((pubkey, addr.port))),
+			AddrV2::I2p(hash) => Some(SockAddr::I2P((hash, addr.port))),
+			AddrV2::Cjdns(ip) if ip.octets()[0] == CJDNS_PREFIX => Some(SockAddr::V6((ip.segments(), addr.port))),
+			_ => None,
+		}
+	}
+	/// Writes the address in the nodes database format: a type byte (0 for IPv4, 1 for IPv6, 2
+	/// for Tor v3 and 3 for I2P), the address and then the (big-endian) port.
+	fn write_to(&self, buf: &mut Vec<u8>) {
+		match self {
+			SockAddr::V4(sa) => { buf.push(0); buf.extend_from_slice(&sa.ip().octets()); },
+			SockAddr::V6((ip, _)) => { buf.push(1); buf.extend_from_slice(&segs_to_ip6(ip).octets()); },
+			SockAddr::TorV3((pubkey, _)) => { buf.push(2); buf.extend_from_slice(pubkey); },
+			SockAddr::I2P((hash, _)) => { buf.push(3); buf.extend_from_slice(hash); },
+		}
+		buf.extend_from_slice(&self.port().to_be_bytes());
+	}
+	fn read_from(reader: &mut ByteReader) -> Option<SockAddr> {
+		match reader.read_array::<1>()?[0] {
+			0 => {
+				let ip = Ipv4Addr::from(reader.read_array::<4>()?);
+				Some(SockAddr::V4(SocketAddrV4::new(ip, u16::from_be_bytes(reader.read_array()?))))
+			},
+			1 => {
+				let ip = Ipv6Addr::from(reader.read_array::<16>()?);
+				Some(SockAddr::V6((ip.segments(), u16::from_be_bytes(reader.read_array()?))))
+			},
+			2 => Some(SockAddr::TorV3((reader.read_array()?, u16::from_be_bytes(reader.read_array()?)))),
+			3 => Some(SockAddr::I2P((reader.read_array()?, u16::from_be_bytes(reader.read_array()?)))),
+			_ => None,
+		}
+	}
 	pub fn port(&self) -> u16 {
 		match *self {
 			SockAddr::V4(sa) => sa.port(),
 			SockAddr::V6((_, port)) => port,
+			SockAddr::TorV3((_, port)) => port,
+			SockAddr::I2P((_, port)) => port,
 		}
 	}
-	pub fn ip(&self) -> IpAddr {
+	/// The IP address of this node, if it has one (OnionCat-encoded Tor v2 addresses included).
+	pub fn ip(&self) -> Option<IpAddr> {
 		match *self {
-			SockAddr::V4(sa) => IpAddr::V4(sa.ip().clone()),
-			SockAddr::V6((ip, _)) => IpAddr::V6(segs_to_ip6(&ip)),
+			SockAddr::V4(sa) => Some(IpAddr::V4(*sa.ip())),
+			SockAddr::V6((ip, _)) => Some(IpAddr::V6(segs_to_ip6(&ip))),
+			SockAddr::TorV3(_) => None,
+			SockAddr::I2P(_) => None,
+		}
+	}
+	pub fn network(&self) -> NetworkType {
+		match *self {
+			SockAddr::V4(_) => NetworkType::IPv4,
+			SockAddr::V6((ip, _)) if segs_to_ip6(&ip).octets()[..6] == ONIONCAT_PREFIX => NetworkType::TorV2,
+			SockAddr::V6((ip, _)) if segs_to_ip6(&ip).octets()[0] == CJDNS_PREFIX => NetworkType::Cjdns,
+			SockAddr::V6(_) => NetworkType::IPv6,
+			SockAddr::TorV3(_) => NetworkType::TorV3,
+			SockAddr::I2P(_) => NetworkType::I2P,
+		}
+	}
+	/// The hostname we have to ask the Tor proxy to connect to, if this is an onion service.
+	pub fn onion_hostname(&self) -> Option<String> {
+		match *self {
+			SockAddr::V6((ip, _)) if self.network() == NetworkType::TorV2 => {
+				Some(encode_base32(&segs_to_ip6(&ip).octets()[6..]) + ".onion")
+			},
+			SockAddr::TorV3((pubkey, _)) => {
+				let mut bytes = [0; 35];
+				bytes[..32].copy_from_slice(&pubkey);
+				bytes[32..34].copy_from_slice(&torv3_checksum(&pubkey));
+				bytes[34] = 3;
+				Some(encode_base32(&bytes) + ".onion")
+			},
+			_ => None,
+		}
+	}
+	/// The hostname we have to ask the SAM bridge to connect to, if this is an I2P node.
+	pub fn i2p_hostname(&self) -> Option<String> {
+		match *self {
+			SockAddr::I2P((hash, _)) => Some(encode_base32(&hash).trim_end_matches('=').to_string() + ".b32.i2p"),
+			_ => None,
+		}
+	}
+}
+
+#[test]
+fn onion_sockaddr_test() {
+	let v3 = "j3mjkeee67zvlqoaar3eqcyy647ddjvmrh72esokyvkvbpyphayfllad.onion:8333";
+	let addr: SockAddr = v3.parse().unwrap();
+	assert!(addr.network() == NetworkType::TorV3);
+	assert_eq!(addr.port(), 8333);
+	assert_eq!(addr.to_string(), v3);
+	// Flip a character in the key and the checksum no longer matches
+	assert!("k3mjkeee67zvlqoaar3eqcyy647ddjvmrh72esokyvkvbpyphayfllad.onion:8333".parse::<SockAddr>().is_err());
+
+	let v2: SockAddr = "nkf5e6b7pl4jfd4a.onion:8333".parse().unwrap();
+	assert!(v2.network() == NetworkType::TorV2);
+	assert_eq!(v2.onion_hostname().unwrap(), "nkf5e6b7pl4jfd4a.onion");
+	assert_eq!(v2.to_string(), "[fd87:d87e:eb43:6a8b:d278:3f7a:f892:8f80]:8333");
+	assert!(v2.to_string().parse::<SockAddr>().unwrap() == v2);
+
+	let i2p = "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p:0";
+	let addr: SockAddr = i2p.parse().unwrap();
+	assert!(addr.network() == NetworkType::I2P);
+	assert_eq!(addr.to_string(), i2p);
+	assert!("ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkd.b32.i2p:0".parse::<SockAddr>().is_err());
+
+	assert!("1.2.3.4:8333".parse::<SockAddr>().unwrap().network() == NetworkType::IPv4);
+	assert!("[2001:db8::1]:8333".parse::<SockAddr>().unwrap().network() == NetworkType::IPv6);
+	assert!("[fc32:17ea:e415:c3bf:9808:149d:b5a2:c9aa]:8333".parse::<SockAddr>().unwrap().network() == NetworkType::Cjdns);
+}
+
+/// How often we look for nodes to evict (see U64Setting::NodeMaxAge and U64Setting::MaxNodes)
+const GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+struct GcStats {
+	last_run: Option<Instant>,
+	evicted_for_age: u64,
+	evicted_over_cap: u64,
+}
+
+/// The connections we've reserved for scans, overall and against each of the per-network caps
+#[derive(Clone, Default)]
+pub struct ConnectionCounts {
+	pub total: usize,
+	pub ipv4: usize,
+	pub ipv6: usize,
+	/// Connections through the Tor proxy
+	pub tor: usize,
+	/// Scans put off since startup as we were at a cap
+	pub deferred: u64,
+	/// Scans put off since startup to limit connections to one subnet or ASN
+	pub rate_limited: u64,
+}
+impl ConnectionCounts {
+	/// Gets the per-network cap a connection to addr counts against (if any), and its count
+	fn network_count(&mut self, addr: &SockAddr) -> Option<(U64Setting, &mut usize)> {
+		match addr.network() {
+			NetworkType::IPv4 => Some((U64Setting::MaxIPv4Connections, &mut self.ipv4)),
+			NetworkType::IPv6 => Some((U64Setting::MaxIPv6Connections, &mut self.ipv6)),
+			NetworkType::TorV2|NetworkType::TorV3 => Some((U64Setting::MaxTorConnections, &mut self.tor)),
+			NetworkType::I2P|NetworkType::Cjdns => None,
 		}
 	}
+
+	/// Reserves a connection to addr if that wouldn't take us over any cap, or regardless if force
+	/// is set.
+	fn try_open(&mut self, addr: &SockAddr, u64_settings: &HashMap<U64Setting, u64>, force: bool) -> bool {
+		let under_cap = |count: usize, setting| {
+			let cap = u64_settings[&setting];
+			cap == 0 || (count as u64) < cap
+		};
+		let total_ok = force || under_cap(self.total, U64Setting::MaxConnections);
+		if let Some((setting, count)) = self.network_count(addr) {
+			if !total_ok || !(force || under_cap(*count, setting)) { return false; }
+			*count += 1;
+		} else if !total_ok { return false; }
+		self.total += 1;
+		true
+	}
+
+	fn close(&mut self, addr: &SockAddr) {
+		self.total -= 1;
+		if let Some((_, count)) = self.network_count(addr) { *count -= 1; }
+	}
+}
+
+#[test]
+fn test_connection_caps() {
+	let network = NetworkParams::from_name("bitcoin", None).unwrap();
+	let (mut u64s, _, _) = default_settings(&network);
+	u64s.insert(U64Setting::MaxConnections, 3);
+	u64s.insert(U64Setting::MaxIPv4Connections, 1);
+	u64s.insert(U64Setting::MaxTorConnections, 0); // ie no cap
+	let v4: SockAddr = "1.2.3.4:8333".parse().unwrap();
+	let v6: SockAddr = "[2001:db8::1]:8333".parse().unwrap();
+	let onion: SockAddr = "j3mjkeee67zvlqoaar3eqcyy647ddjvmrh72esokyvkvbpyphayfllad.onion:8333".parse().unwrap();
+	let mut counts = ConnectionCounts::default();
+	assert!(counts.try_open(&v4, &u64s, false));
+	// IPv4 is full, but other networks aren't
+	assert!(!counts.try_open(&v4, &u64s, false));
+	assert!(counts.try_open(&v6, &u64s, false));
+	assert!(counts.try_open(&onion, &u64s, false));
+	// Now we're at the overall cap, which only manual scans can go over
+	assert!(!counts.try_open(&onion, &u64s, false));
+	assert!(counts.try_open(&v4, &u64s, true));
+	assert_eq!((counts.total, counts.ipv4, counts.ipv6, counts.tor), (4, 2, 1, 1));
+	counts.close(&v4);
+	counts.close(&v4);
+	counts.close(&onion);
+	assert!(counts.try_open(&v4, &u64s, false));
+	assert_eq!((counts.total, counts.ipv4, counts.tor), (2, 1, 0));
+}
+
+/// Splits the nodes to scan this round into those we'll scan now and those to put off, so that we
+/// make at most max_per_subnet connections to any one IPv4 /24 or IPv6 /48, and at most
+/// max_per_asn to any one ASN (0 for no limit). Nodes on other networks, and the ASN limit for IPs
+/// we have no route for, aren't limited.
+pub fn limit_per_subnet_and_asn<F: Fn(IpAddr) -> u32>(nodes: Vec<SockAddr>, get_asn: F, max_per_subnet: u64, max_per_asn: u64) -> (Vec<SockAddr>, Vec<SockAddr>) {
+	let mut subnet_counts: HashMap<(u8, [u8; 6]), u64> = HashMap::new();
+	let mut asn_counts: HashMap<u32, u64> = HashMap::new();
+	let under_limit = |count: u64, limit: u64| limit == 0 || count < limit;
+	nodes.into_iter().partition(|addr| {
+		let subnet = match (addr.network(), addr.ip()) {
+			(NetworkType::IPv4, Some(IpAddr::V4(ip))) => { let o = ip.octets(); (4, [o[0], o[1], o[2], 0, 0, 0]) },
+			(NetworkType::IPv6, Some(IpAddr::V6(ip))) => (6, ip.octets()[..6].try_into().unwrap()),
+			_ => return true,
+		};
+		let subnet_count = subnet_counts.entry(subnet).or_insert(0);
+		if !under_limit(*subnet_count, max_per_subnet) { return false; }
+		let asn = if max_per_asn != 0 { get_asn(addr.ip().unwrap()) } else { 0 };
+		if asn != 0 {
+			let asn_count = asn_counts.entry(asn).or_insert(0);
+			if !under_limit(*asn_count, max_per_asn) { return false; }
+			*asn_count += 1;
+		}
+		*subnet_count += 1;
+		true
+	})
+}
+
+#[test]
+fn test_subnet_and_asn_limits() {
+	let addrs = ["1.2.3.4:8333", "1.2.3.5:8333", "1.2.3.6:8333", "1.2.4.1:8333", "5.6.7.8:8333", "9.9.9.9:8333",
+		"[2001:db8::1]:8333", "[2001:db8::2]:8333", "[2001:db8:0:1::1]:8333", "[2001:db8:1::1]:8333", "[2001:db9::1]:8333",
+		"j3mjkeee67zvlqoaar3eqcyy647ddjvmrh72esokyvkvbpyphayfllad.onion:8333"];
+	let nodes: Vec<SockAddr> = addrs.iter().map(|addr| addr.parse().unwrap()).collect();
+	// 1.0.0.0/8 is all one ASN, as is 2001:db8::/32, and we have no route for 9.9.9.9
+	let get_asn = |ip: IpAddr| match ip {
+		IpAddr::V4(ip) if ip.octets()[0] == 1 => 64496,
+		IpAddr::V4(ip) if ip.octets()[0] == 5 => 64497,
+		IpAddr::V6(ip) if ip.segments()[1] == 0xdb8 => 64498,
+		_ => 0,
+	};
+	let to_strings = |nodes: Vec<SockAddr>| nodes.iter().map(|addr| addr.to_string()).collect::<Vec<_>>();
+	let (scan, deferred) = limit_per_subnet_and_asn(nodes.clone(), get_asn, 2, 0);
+	assert_eq!(to_strings(deferred), vec!["1.2.3.6:8333", "[2001:db8:0:1::1]:8333"]);
+	assert_eq!(scan.len(), 10);
+	let (scan, deferred) = limit_per_subnet_and_asn(nodes.clone(), get_asn, 2, 2);
+	assert_eq!(to_strings(deferred), vec!["1.2.3.6:8333", "1.2.4.1:8333", "[2001:db8:0:1::1]:8333", "[2001:db8:1::1]:8333"]);
+	assert_eq!(scan.len(), 8);
+	let (scan, deferred) = limit_per_subnet_and_asn(nodes, get_asn, 0, 0);
+	assert!(deferred.is_empty());
+	assert_eq!(scan.len(), addrs.len());
+}
+
+/// How often we save the timeout filter (which is 256MiB) to timeout_bloom
+const BLOOM_SAVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The nodes database (nodes.db) starts with this magic, followed by a u32 format version and
+/// a u64 node count, then the nodes, and ends with the SHA256 of everything before it. All
+/// integers and floats are little-endian.
+const NODES_DB_MAGIC: &[u8; 8] = b"DNSSEEDN";
+const NODES_DB_VERSION: u32 = 1;
+
+/// A cursor over a byte slice for reading the nodes database.
+struct ByteReader<'a> {
+	data: &'a [u8],
+	pos: usize,
+}
+impl<'a> ByteReader<'a> {
+	fn read_array<const N: usize>(&mut self) -> Option<[u8; N]> {
+		let res = self.data.get(self.pos..self.pos + N)?.try_into().unwrap();
+		self.pos += N;
+		Some(res)
+	}
+	fn read_string(&mut self) -> Option<String> {
+		let len = u16::from_le_bytes(self.read_array()?) as usize;
+		let res = String::from_utf8_lossy(self.data.get(self.pos..self.pos + len)?).into_owned();
+		self.pos += len;
+		Some(res)
+	}
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+	let mut len = std::cmp::min(s.len(), u16::MAX as usize);
+	while !s.is_char_boundary(len) { len -= 1; }
+	buf.extend_from_slice(&(len as u16).to_le_bytes());
+	buf.extend_from_slice(&s.as_bytes()[..len]);
+}
+
+#[test]
+fn test_nodes_db() {
+	let csv = "1.2.3.4:8333,12,1033\n\
+		[2001:db8::1]:18333,12,9,1700000000,1700000100,1700000200,1,2,0.5,1,2,0.5,1,2,0.5,1,2,0.5,1,2,0.5\n\
+		ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p:0,0,0,0,0,0\n";
+	let mut nodes = Nodes::from_csv(csv.as_bytes()).unwrap();
+	assert_eq!(nodes.nodes_to_state.len(), 3);
+	assert_eq!(nodes.reliability.len(), 1);
+	assert_eq!(nodes.good_node_services[10].len(), 1);
+	assert_eq!(nodes.network_node_counts[NetworkType::IPv6.to_num() as usize], (1, 1));
+	// Old three-column lines are Good as of now
+	assert!(nodes.nodes_to_state[&"1.2.3.4:8333".parse().unwrap()].last_good > 1700000000);
+
+	// Round-trip through the nodes database, with some scan history and details
+	let v6_addr: SockAddr = "[2001:db8::1]:18333".parse().unwrap();
+	let v6_node = nodes.nodes_to_state.get_mut(&v6_addr).unwrap();
+	assert!(v6_node.history().is_empty());
+	v6_node.push_history(AddressState::Timeout);
+	v6_node.push_history(AddressState::Good);
+	v6_node.failures = 5;
+	v6_node.first_seen = 1600000000;
+	nodes.details.insert(v6_addr.clone(), NodeDetails {
+		version: Some(VersionInfo { user_agent: "/Satoshi:27.0.0/".to_string(), protocol_version: 70016, services: 9, start_height: 850000 }),
+		version_time: 1700000100, rtt_ms: Some(42), last_message: "Good".to_string(),
+	});
+	let data = nodes.serialize();
+	assert_eq!(&data[..8], b"DNSSEEDN");
+	let loaded = Nodes::deserialize(&data).unwrap();
+	assert_eq!(loaded.nodes_to_state.len(), 3);
+	let v6 = &loaded.nodes_to_state[&"[2001:db8::1]:18333".parse().unwrap()];
+	assert!(v6.state == AddressState::Good);
+	assert_eq!((v6.last_services(), v6.last_good, v6.last_seen, v6.last_attempt), (9, 1700000000, 1700000100, 1700000200));
+	assert_eq!(loaded.reliability[&"[2001:db8::1]:18333".parse().unwrap()].uptime(4), 0.5);
+	assert_eq!(v6.failures, 5);
+	assert_eq!(v6.first_seen, 1600000000);
+	let history = v6.history();
+	assert_eq!(history.len(), 2);
+	assert!(history[0] == AddressState::Good && history[1] == AddressState::Timeout);
+	let details = &loaded.details[&v6_addr];
+	let version = details.version.as_ref().unwrap();
+	assert_eq!((&version.user_agent[..], version.protocol_version, version.services, version.start_height), ("/Satoshi:27.0.0/", 70016, 9, 850000));
+	assert_eq!((details.version_time, details.rtt_ms, &details.last_message[..]), (1700000100, Some(42), "Good"));
+	assert_eq!(loaded.details.len(), 1);
+	assert_eq!(loaded.state_node_counts[AddressState::Good.to_num() as usize], 2);
+	assert!(loaded.nodes_to_state.contains_key(&"ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p:0".parse().unwrap()));
+
+	// Any corruption fails the whole load rather than loading a prefix
+	for i in [0, 10, 20, data.len() / 2, data.len() - 1].iter() {
+		let mut corrupted = data.clone();
+		corrupted[*i] ^= 1;
+		assert!(Nodes::deserialize(&corrupted).is_err());
+	}
+	assert!(Nodes::deserialize(&data[..data.len() - 1]).is_err());
+	let mut future_version = data[..data.len() - 32].to_vec();
+	future_version[8] = 2;
+	let checksum = sha256::Hash::hash(&future_version);
+	future_version.extend_from_slice(&checksum[..]);
+	assert!(Nodes::deserialize(&future_version).is_err());
+
+	assert!(Nodes::from_csv("1.2.3.4:8333,12,1033\n1.2.3.5:8333,x,1\n".as_bytes()).is_err());
+	assert!(Nodes::from_csv("1.2.3.4:8333,99,1033\n".as_bytes()).is_err());
+}
+
+#[test]
+fn test_nodes_gc() {
+	let csv = "1.0.0.1:8333,12,1,100,100,100\n\
+		1.0.0.2:8333,13,1,100,100,100\n\
+		1.0.0.3:8333,5,0,0,100,100\n\
+		1.0.0.4:8333,5,0,0,900,900\n\
+		1.0.0.5:8333,0,0,0,0,0\n\
+		1.0.0.6:8333,7,0,0,0,800\n\
+		1.0.0.7:8333,7,0,0,0,900\n";
+	let mut nodes = Nodes::from_csv(csv.as_bytes()).unwrap();
+	for node in nodes.nodes_to_state.values_mut() { node.first_seen = 100; }
+	// A node we've never reached is kept for max_age after we first heard of it
+	nodes.nodes_to_state.get_mut(&"1.0.0.7:8333".parse().unwrap()).unwrap().first_seen = 800;
+	nodes.schedule_loaded(&default_settings(&NetworkParams::from_name("bitcoin", None).unwrap()).0, 1000);
+	nodes.details.insert("1.0.0.3:8333".parse().unwrap(), NodeDetails { version: None, version_time: 0, rtt_ms: None, last_message: String::new() });
+
+	// Good and WasGood nodes, and nodes we haven't tried yet, are never stale
+	assert_eq!(nodes.gc(1000, 500, 0), (2, 0));
+	assert_eq!(nodes.nodes_to_state.len(), 5);
+	assert!(nodes.nodes_to_state.contains_key(&"1.0.0.7:8333".parse().unwrap()));
+	assert!(!nodes.nodes_to_state.contains_key(&"1.0.0.3:8333".parse().unwrap()));
+	assert!(!nodes.nodes_to_state.contains_key(&"1.0.0.6:8333".parse().unwrap()));
+	assert!(nodes.details.is_empty());
+	assert_eq!(nodes.network_node_counts[NetworkType::IPv4.to_num() as usize], (5, 1));
+	assert_eq!(nodes.state_node_counts.iter().sum::<usize>(), 5);
+	// ...but not once max_age has passed
+	assert_eq!(nodes.gc(1400, 500, 0), (1, 0));
+	assert!(!nodes.nodes_to_state.contains_key(&"1.0.0.7:8333".parse().unwrap()));
+
+	// Over the cap, the node we know least about goes first
+	assert_eq!(nodes.gc(1000, 0, 3), (0, 1));
+	assert!(!nodes.nodes_to_state.contains_key(&"1.0.0.5:8333".parse().unwrap()));
+	// Nodes being scanned are left alone, as the scan's result would only bring them back
+	let addr: SockAddr = "1.0.0.4:8333".parse().unwrap();
+	let due = nodes.nodes_to_state[&addr].next_scan;
+	nodes.nodes_to_state.get_mut(&addr).unwrap().next_scan = 0;
+	assert_eq!(nodes.gc(1000, 0, 1), (0, 0));
+	nodes.nodes_to_state.get_mut(&addr).unwrap().next_scan = due;
+	assert_eq!(nodes.gc(1000, 0, 1), (0, 1));
+	assert_eq!(nodes.nodes_to_state.len(), 2);
+	assert_eq!(nodes.gc(1000, 0, 0), (0, 0));
+
+	// Evicted nodes' queue entries are skipped
+	let reachable = [true; NetworkType::get_count() as usize];
+	let u64_settings = default_settings(&NetworkParams::from_name("bitcoin", None).unwrap()).0;
+	assert_eq!(nodes.take_due_nodes(u32::MAX, &reachable, &u64_settings, 100).len(), 2);
+}
+
+#[test]
+fn test_scan_schedule() {
+	let (mut u64_settings, _, _) = default_settings(&NetworkParams::from_name("bitcoin", None).unwrap());
+	u64_settings.insert(U64Setting::RescanInterval(AddressState::Untested), 60);
+	let csv = "1.0.0.1:8333,12,1,19000,19000,19000\n\
+		1.0.0.2:8333,5,0,0,100,100\n\
+		1.0.0.3:8333,0,0,0,0,0\n\
+		1.0.0.4:8333,0,0,0,0,0\n\
+		[2001:db8::1]:8333,0,0,0,0,0\n";
+	let mut nodes = Nodes::from_csv(csv.as_bytes()).unwrap();
+	nodes.schedule_loaded(&u64_settings, 20000);
+	let due = |nodes: &Nodes, addr: &str| nodes.nodes_to_state[&addr.parse().unwrap()].next_scan;
+
+	// Nodes are due a rescan interval after we last scanned them, and overdue ones are spread out
+	assert_eq!(due(&nodes, "1.0.0.1:8333"), 19000 + 1800);
+	assert_eq!(due(&nodes, "1.0.0.2:8333"), 100 + 86400);
+	assert!(due(&nodes, "1.0.0.3:8333") >= 20000 && due(&nodes, "1.0.0.3:8333") <= 20060);
+
+	// Only one Untested node is taken per round, and unreachable ones are put back
+	let mut reachable = [true; NetworkType::get_count() as usize];
+	reachable[NetworkType::IPv6.to_num() as usize] = false;
+	let taken = nodes.take_due_nodes(20800, &reachable, &u64_settings, 1);
+	assert_eq!(taken.len(), 2);
+	assert!(taken.contains(&"1.0.0.1:8333".parse().unwrap()));
+	assert_eq!(due(&nodes, "1.0.0.1:8333"), 0);
+	assert_eq!(nodes.take_due_nodes(20800, &reachable, &u64_settings, 1).len(), 1);
+	assert!(nodes.take_due_nodes(20800, &reachable, &u64_settings, 1).is_empty());
+	assert_eq!(due(&nodes, "[2001:db8::1]:8333"), 20800 + 60);
+
+	// Nodes which keep failing back off exponentially, up to the state's cap
+	let node = nodes.nodes_to_state.get_mut(&"1.0.0.2:8333".parse().unwrap()).unwrap();
+	assert_eq!(rescan_interval(&u64_settings, node), 86400);
+	node.failures = 3;
+	assert_eq!(rescan_interval(&u64_settings, node), 86400 * 4);
+	node.failures = 200;
+	assert_eq!(rescan_interval(&u64_settings, node), 86400 * 8);
+
+	// A moved node is only taken at its new time
+	let addr: SockAddr = "1.0.0.2:8333".parse().unwrap();
+	schedule_scan(&mut nodes.scan_queue, &addr, nodes.nodes_to_state.get_mut(&addr).unwrap(), 30000);
+	assert!(nodes.take_due_nodes(30000, &reachable, &u64_settings, 1) == vec![addr]);
+	assert!(nodes.take_due_nodes(86500, &reachable, &u64_settings, 1).is_empty());
+}
+
+/// The longest we spread the scans of overdue nodes over when we start up
+const SCAN_SPREAD_SECS: u64 = 60 * 60;
+
+/// How long after a scan we should rescan a node, given its new state. Each consecutive failure
+/// after the first doubles the state's rescan interval, up to its MaxRescanInterval.
+fn rescan_interval(u64_settings: &HashMap<U64Setting, u64>, node: &Node) -> u64 {
+	let interval = cmp::max(u64_settings[&U64Setting::RescanInterval(node.state)], 1);
+	let backoff = interval.saturating_mul(1 << cmp::min(node.failures.saturating_sub(1), 32));
+	cmp::max(cmp::min(backoff, u64_settings[&U64Setting::MaxRescanInterval(node.state)]), interval)
+}
+
+/// Nodes waiting to be scanned, with a queue per AddressState keyed by when they're due (in UNIX
+/// seconds). Entries are dropped lazily: an entry is only live if its node still exists and its
+/// next_scan is the entry's due time, so moving or evicting a queued node is O(1).
+#[derive(Default)]
+struct ScanQueue([BTreeMap<u32, Vec<SockAddr>>; AddressState::get_count() as usize]);
+
+/// Queues a node to be scanned at the given time, moving it if it was already queued.
+fn schedule_scan(scan_queue: &mut ScanQueue, addr: &SockAddr, node: &mut Node, due: u64) {
+	let due = cmp::max(cmp::min(due, u32::MAX as u64) as u32, 1);
+	if node.next_scan == due { return; }
+	scan_queue.0[node.state.to_num() as usize].entry(due).or_default().push(addr.clone());
+	node.next_scan = due;
 }
 
 struct Nodes {
 	good_node_services: [HashSet<SockAddr>; 64],
 	nodes_to_state: HashMap<SockAddr, Node>,
+	/// Kept separately from Node as most nodes are never Good and don't need one
+	reliability: HashMap<SockAddr, Reliability>,
+	/// Only for nodes which have responded to a scan at some point
+	details: HashMap<SockAddr, NodeDetails>,
 	timeout_nodes: RollingBloomFilter<SockAddr>,
-	state_next_scan: [Vec<SockAddr>; AddressState::get_count() as usize],
+	scan_queue: ScanQueue,
+	/// Node counts, indexed by AddressState
+	state_node_counts: [usize; AddressState::get_count() as usize],
+	/// (total, Good) node counts, indexed by NetworkType
+	network_node_counts: [(usize, usize); NetworkType::get_count() as usize],
 }
 struct NodesMutRef<'a> {
 	good_node_services: &'a mut [HashSet<SockAddr>; 64],
 	nodes_to_state: &'a mut HashMap<SockAddr, Node>,
+	reliability: &'a mut HashMap<SockAddr, Reliability>,
+	details: &'a mut HashMap<SockAddr, NodeDetails>,
 	timeout_nodes: &'a mut RollingBloomFilter<SockAddr>,
-	state_next_scan: &'a mut [Vec<SockAddr>; AddressState::get_count() as usize],
+	scan_queue: &'a mut ScanQueue,
+	state_node_counts: &'a mut [usize; AddressState::get_count() as usize],
+	network_node_counts: &'a mut [(usize, usize); NetworkType::get_count() as usize],
 }
 
 impl Nodes {
+	fn new() -> Nodes {
+		let good_node_services = [HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new()];
+		Nodes {
+			good_node_services,
+			nodes_to_state: HashMap::new(),
+			reliability: HashMap::new(),
+			details: HashMap::new(),
+			timeout_nodes: RollingBloomFilter::new(),
+			scan_queue: ScanQueue::default(),
+			state_node_counts: [0; AddressState::get_count() as usize],
+			network_node_counts: [(0, 0); NetworkType::get_count() as usize],
+		}
+	}
+
+	/// Adds a node loaded from disk. It isn't queued for scanning until schedule_loaded.
+	fn insert_loaded(&mut self, sockaddr: SockAddr, node: Node, reliability: Option<Reliability>, details: Option<NodeDetails>) {
+		let network_counts = &mut self.network_node_counts[sockaddr.network().to_num() as usize];
+		network_counts.0 += 1;
+		if node.state == AddressState::Good {
+			network_counts.1 += 1;
+			for i in 0..64 {
+				if node.last_services() & (1 << i) != 0 {
+					self.good_node_services[i].insert(sockaddr.clone());
+				}
+			}
+		}
+		if let Some(reliability) = reliability {
+			self.reliability.insert(sockaddr.clone(), reliability);
+		}
+		if let Some(details) = details {
+			self.details.insert(sockaddr.clone(), details);
+		}
+		self.state_node_counts[node.state.to_num() as usize] += 1;
+		self.nodes_to_state.insert(sockaddr, node);
+	}
+
+	/// Queues every node to be scanned when it's next due given when we last scanned it. Nodes
+	/// which are already due are spread over the next SCAN_SPREAD_SECS (or their rescan interval,
+	/// if it's shorter), so that we don't rescan everything at once after a restart.
+	fn schedule_loaded(&mut self, u64_settings: &HashMap<U64Setting, u64>, now: u32) {
+		let mut rng = thread_rng();
+		for (addr, node) in self.nodes_to_state.iter_mut() {
+			let interval = rescan_interval(u64_settings, node);
+			let due = node.last_attempt as u64 + interval;
+			let due = if due > now as u64 { due } else {
+				now as u64 + rng.gen_range(0..=cmp::min(interval, SCAN_SPREAD_SECS))
+			};
+			schedule_scan(&mut self.scan_queue, addr, node, due);
+		}
+	}
+
+	/// Loads nodes from the CSV nodes file we used to write (addr,state,services, followed by
+	/// timestamps and reliability stats in later versions).
+	fn from_csv<R: BufRead>(reader: R) -> Result<Nodes, std::io::Error> {
+		let mut res = Nodes::new();
+		// Nodes files don't say when we first heard of each node, so give them a full max_age
+		let migrated_at = unix_time();
+		for (line_idx, line_res) in reader.lines().enumerate() {
+			let line = line_res?;
+			let err = || std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Bad nodes file line {}: {}", line_idx + 1, line));
+			let mut line_iter = line.split(',');
+			macro_rules! try_read {
+				($lines: expr, $ty: ty) => { {
+					try_read!($lines, $ty, return Err(err()))
+				} };
+				($lines: expr, $ty: ty, $eof: expr) => { {
+					match $lines.next() {
+						Some(field) => field.parse::<$ty>().map_err(|_| err())?,
+						None => $eof,
+					}
+				} }
+			}
+			let sockaddr = try_read!(line_iter, SockAddr);
+			let state = AddressState::from_num(try_read!(line_iter, u8)).ok_or_else(err)?;
+			let last_services = try_read!(line_iter, u64);
+			// Older nodes files don't have timestamps. Treat Good nodes from them as Good as of
+			// now, as we did before we had timestamps, so that they aren't immediately forgotten.
+			let last_good = try_read!(line_iter, u32, unix_time());
+			let last_seen = try_read!(line_iter, u32, 0);
+			let last_attempt = try_read!(line_iter, u32, 0);
+			// Followed by the weight, count and uptime in each window, for nodes which have them
+			let reliability = if line_iter.clone().next().is_some() {
+				let mut reliability = Reliability::default();
+				for stat in reliability.0.iter_mut() {
+					stat.weight = try_read!(line_iter, f32);
+					stat.count = try_read!(line_iter, f32);
+					stat.reliability = try_read!(line_iter, f32);
+				}
+				Some(reliability)
+			} else { None };
+			if line_iter.next().is_some() { return Err(err()); }
+			res.insert_loaded(sockaddr, Node {
+				state,
+				last_services: Node::services(last_services),
+				last_good,
+				last_seen,
+				first_seen: migrated_at,
+				last_attempt,
+				next_scan: 0,
+				dns_seed: 0,
+				failures: 0,
+				history: EMPTY_HISTORY,
+			}, reliability, None);
+		}
+		Ok(res)
+	}
+
+	/// Serializes all nodes in the nodes database format (see NODES_DB_MAGIC).
+	fn serialize(&self) -> Vec<u8> {
+		let mut res = Vec::with_capacity(self.nodes_to_state.len() * 64);
+		res.extend_from_slice(NODES_DB_MAGIC);
+		res.extend_from_slice(&NODES_DB_VERSION.to_le_bytes());
+		res.extend_from_slice(&(self.nodes_to_state.len() as u64).to_le_bytes());
+		for (sockaddr, node) in self.nodes_to_state.iter() {
+			sockaddr.write_to(&mut res);
+			res.push(node.state.to_num());
+			res.extend_from_slice(&node.last_services().to_le_bytes());
+			res.extend_from_slice(&node.last_good.to_le_bytes());
+			res.extend_from_slice(&node.last_seen.to_le_bytes());
+			res.extend_from_slice(&node.first_seen.to_le_bytes());
+			res.extend_from_slice(&node.last_attempt.to_le_bytes());
+			match self.reliability.get(sockaddr) {
+				Some(reliability) => {
+					res.push(1);
+					for stat in reliability.0.iter() {
+						res.extend_from_slice(&stat.weight.to_le_bytes());
+						res.extend_from_slice(&stat.count.to_le_bytes());
+						res.extend_from_slice(&stat.reliability.to_le_bytes());
+					}
+				},
+				None => res.push(0),
+			}
+			res.extend_from_slice(&node.history.to_le_bytes());
+			res.push(node.failures);
+			match self.details.get(sockaddr) {
+				Some(details) => {
+					res.push(1);
+					match details.version {
+						Some(ref version) => {
+							res.push(1);
+							write_string(&mut res, &version.user_agent);
+							res.extend_from_slice(&version.protocol_version.to_le_bytes());
+							res.extend_from_slice(&version.services.to_le_bytes());
+							res.extend_from_slice(&version.start_height.to_le_bytes());
+						},
+						None => res.push(0),
+					}
+					res.extend_from_slice(&details.version_time.to_le_bytes());
+					match details.rtt_ms {
+						Some(rtt) => { res.push(1); res.extend_from_slice(&rtt.to_le_bytes()); },
+						None => res.push(0),
+					}
+					write_string(&mut res, &details.last_message);
+				},
+				None => res.push(0),
+			}
+		}
+		let checksum = sha256::Hash::hash(&res);
+		res.extend_from_slice(&checksum[..]);
+		res
+	}
+
+	/// Loads a nodes database, failing on any corruption rather than loading part of it.
+	fn deserialize(data: &[u8]) -> Result<Nodes, std::io::Error> {
+		let err = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Corrupt nodes database: {}", msg));
+		if data.len() < NODES_DB_MAGIC.len() + 4 + 8 + 32 || &data[..NODES_DB_MAGIC.len()] != NODES_DB_MAGIC {
+			return Err(err("bad header"));
+		}
+		let (contents, checksum) = data.split_at(data.len() - 32);
+		if sha256::Hash::hash(contents)[..] != *checksum {
+			return Err(err("checksum mismatch"));
+		}
+		let mut reader = ByteReader { data: contents, pos: NODES_DB_MAGIC.len() };
+		let version = u32::from_le_bytes(reader.read_array().ok_or_else(|| err("truncated"))?);
+		if version != NODES_DB_VERSION {
+			return Err(err(&format!("unsupported version {} (we only know version {})", version, NODES_DB_VERSION)));
+		}
+		let count = u64::from_le_bytes(reader.read_array().ok_or_else(|| err("truncated"))?);
+		let mut res = Nodes::new();
+		for _ in 0..count {
+			let (sockaddr, node, reliability, details) = Nodes::read_node(&mut reader).ok_or_else(|| err("bad node entry"))?;
+			res.insert_loaded(sockaddr, node, reliability, details);
+		}
+		if reader.pos != contents.len() {
+			return Err(err("trailing data"));
+		}
+		Ok(res)
+	}
+
+	fn read_node(reader: &mut ByteReader) -> Option<(SockAddr, Node, Option<Reliability>, Option<NodeDetails>)> {
+		let sockaddr = SockAddr::read_from(reader)?;
+		let state = AddressState::from_num(reader.read_array::<1>()?[0])?;
+		let last_services = u64::from_le_bytes(reader.read_array()?);
+		let last_good = u32::from_le_bytes(reader.read_array()?);
+		let last_seen = u32::from_le_bytes(reader.read_array()?);
+		let first_seen = u32::from_le_bytes(reader.read_array()?);
+		let last_attempt = u32::from_le_bytes(reader.read_array()?);
+		let reliability = match reader.read_array::<1>()?[0] {
+			0 => None,
+			1 => {
+				let mut reliability = Reliability::default();
+				for stat in reliability.0.iter_mut() {
+					stat.weight = f32::from_le_bytes(reader.read_array()?);
+					stat.count = f32::from_le_bytes(reader.read_array()?);
+					stat.reliability = f32::from_le_bytes(reader.read_array()?);
+				}
+				Some(reliability)
+			},
+			_ => return None,
+		};
+		let history = u32::from_le_bytes(reader.read_array()?);
+		let failures = reader.read_array::<1>()?[0];
+		let details = match reader.read_array::<1>()?[0] {
+			0 => None,
+			1 => {
+				let version = match reader.read_array::<1>()?[0] {
+					0 => None,
+					1 => Some(VersionInfo {
+						user_agent: reader.read_string()?,
+						protocol_version: u32::from_le_bytes(reader.read_array()?),
+						services: u64::from_le_bytes(reader.read_array()?),
+						start_height: i32::from_le_bytes(reader.read_array()?),
+					}),
+					_ => return None,
+				};
+				let version_time = u32::from_le_bytes(reader.read_array()?);
+				let rtt_ms = match reader.read_array::<1>()?[0] {
+					0 => None,
+					1 => Some(u32::from_le_bytes(reader.read_array()?)),
+					_ => return None,
+				};
+				Some(NodeDetails { version, version_time, rtt_ms, last_message: reader.read_string()? })
+			},
+			_ => return None,
+		};
+		Some((sockaddr, Node {
+			state,
+			last_services: Node::services(last_services),
+			last_good,
+			last_seen,
+			first_seen,
+			last_attempt,
+			next_scan: 0,
+			dns_seed: 0,
+			failures,
+			history,
+		}, reliability, details))
+	}
+
+	/// Evicts nodes which aren't Good, WasGood or being scanned and haven't been Good or responded
+	/// to us for max_age seconds, or for max_age since we first heard of them if they never have
+	/// (if it's non-0, and once we've tried them at least once), then, if we still have more than
+	/// max_nodes (if it's non-0), those which were Good, responded or were added the longest ago. Returns the number evicted for age and the number evicted over the cap.
+	fn gc(&mut self, now: u32, max_age: u32, max_nodes: usize) -> (usize, usize) {
+		let mut evict = HashSet::new();
+		let mut candidates = Vec::new();
+		for (addr, node) in self.nodes_to_state.iter() {
+			if node.state == AddressState::Good || node.state == AddressState::WasGood { continue; }
+			// set_node_state would re-add a node evicted mid-scan once the scan finishes
+			if node.next_scan == 0 { continue; }
+			let last_alive = cmp::max(node.first_seen, cmp::max(node.last_good, node.last_seen));
+			if max_age != 0 && node.last_attempt != 0 && now.saturating_sub(last_alive) > max_age {
+				evict.insert(addr.clone());
+			} else {
+				candidates.push((last_alive, node.last_attempt, addr));
+			}
+		}
+		let evicted_for_age = evict.len();
+		let remaining = self.nodes_to_state.len() - evicted_for_age;
+		if max_nodes != 0 && remaining > max_nodes {
+			candidates.sort_unstable_by_key(|(last_alive, last_attempt, _)| (*last_alive, *last_attempt));
+			evict.extend(candidates.into_iter().take(remaining - max_nodes).map(|(_, _, addr)| addr.clone()));
+		}
+		if evict.is_empty() { return (0, 0); }
+
+		for addr in evict.iter() {
+			// Any scan_queue entry is skipped once the node is gone
+			let node = self.nodes_to_state.remove(addr).unwrap();
+			self.reliability.remove(addr);
+			self.details.remove(addr);
+			self.network_node_counts[addr.network().to_num() as usize].0 -= 1;
+			self.state_node_counts[node.state.to_num() as usize] -= 1;
+		}
+		(evicted_for_age, evict.len() - evicted_for_age)
+	}
+
+	/// Takes the nodes which are due to be scanned by now, up to max_per_state from each state's
+	/// queue (the rest stay first in line for next time). Nodes on unreachable networks are put
+	/// back for another rescan interval.
+	fn take_due_nodes(&mut self, now: u32, reachable_networks: &[bool; NetworkType::get_count() as usize],
+			u64_settings: &HashMap<U64Setting, u64>, max_per_state: u64) -> Vec<SockAddr> {
+		let mut res = Vec::new();
+		let mut unreachable = Vec::new();
+		for queue in self.scan_queue.0.iter_mut() {
+			let mut taken = 0;
+			while taken < max_per_state {
+				let mut due_nodes = match queue.first_entry() {
+					Some(due_nodes) if *due_nodes.key() <= now => due_nodes,
+					_ => break,
+				};
+				let due = *due_nodes.key();
+				while taken < max_per_state {
+					let addr = match due_nodes.get_mut().pop() {
+						Some(addr) => addr,
+						None => break,
+					};
+					let node = match self.nodes_to_state.get_mut(&addr) {
+						Some(node) if node.next_scan == due => node,
+						_ => continue,
+					};
+					node.next_scan = 0;
+					if !reachable_networks[addr.network().to_num() as usize] {
+						unreachable.push(addr);
+					} else {
+						taken += 1;
+						res.push(addr);
+					}
+				}
+				if due_nodes.get().is_empty() { due_nodes.remove(); }
+			}
+		}
+		for addr in unreachable {
+			let node = self.nodes_to_state.get_mut(&addr).unwrap();
+			let interval = rescan_interval(u64_settings, node);
+			schedule_scan(&mut self.scan_queue, &addr, node, now as u64 + interval);
+		}
+		res
+	}
+
 	fn borrow_mut<'a>(&'a mut self) -> NodesMutRef<'a> {
 		NodesMutRef {
 			good_node_services: &mut self.good_node_services,
 			nodes_to_state: &mut self.nodes_to_state,
+			reliability: &mut self.reliability,
+			details: &mut self.details,
 			timeout_nodes: &mut self.timeout_nodes,
-			state_next_scan: &mut self.state_next_scan,
+			scan_queue: &mut self.scan_queue,
+			state_node_counts: &mut self.state_node_counts,
+			network_node_counts: &mut self.network_node_counts,
+		}
+	}
+}
+
+/// The u64 settings, subversion regex and upstream DNS seeds, as loaded from the settings file
+type Settings = (HashMap<U64Setting, u64>, Regex, Vec<String>);
+
+fn default_settings(network: &NetworkParams) -> Settings {
+	let mut u64s = HashMap::with_capacity(15);
+	u64s.insert(U64Setting::RunTimeout, 120);
+	u64s.insert(U64Setting::WasGoodTimeout, 21600);
+	u64s.insert(U64Setting::RescanInterval(AddressState::Untested), 3600);
+	u64s.insert(U64Setting::RescanInterval(AddressState::LowBlockCount), 3600);
+	u64s.insert(U64Setting::RescanInterval(AddressState::HighBlockCount), 7200);
+	u64s.insert(U64Setting::RescanInterval(AddressState::LowVersion), 21600);
+	u64s.insert(U64Setting::RescanInterval(AddressState::BadVersion), 21600);
+	u64s.insert(U64Setting::RescanInterval(AddressState::NotFullNode), 86400);
+	u64s.insert(U64Setting::RescanInterval(AddressState::ProtocolViolation), 86400);
+	u64s.insert(U64Setting::RescanInterval(AddressState::Timeout), 604800);
+	u64s.insert(U64Setting::RescanInterval(AddressState::TimeoutDuringRequest), 21600);
+	u64s.insert(U64Setting::RescanInterval(AddressState::TimeoutAwaitingPong), 3600);
+	u64s.insert(U64Setting::RescanInterval(AddressState::TimeoutAwaitingAddr), 1800);
+	u64s.insert(U64Setting::RescanInterval(AddressState::TimeoutAwaitingBlock), 3600);
+	u64s.insert(U64Setting::RescanInterval(AddressState::Good), 1800);
+	u64s.insert(U64Setting::RescanInterval(AddressState::WasGood), 1800);
+	u64s.insert(U64Setting::RescanInterval(AddressState::EvilNode), 315360000);
+	u64s.insert(U64Setting::MinProtocolVersion, 70002);
+	u64s.insert(U64Setting::AllowedDnsServices, DEFAULT_ALLOWED_DNS_SERVICES);
+	u64s.insert(U64Setting::NodeMaxAge, 2592000);
+	u64s.insert(U64Setting::MaxNodes, 10_000_000);
+	// Each connection is a file descriptor, and Tor circuits are much more expensive
+	u64s.insert(U64Setting::MaxConnections, 16384);
+	u64s.insert(U64Setting::MaxIPv4Connections, 12288);
+	u64s.insert(U64Setting::MaxIPv6Connections, 8192);
+	u64s.insert(U64Setting::MaxTorConnections, 1024);
+	// Enough that we keep up with big hosting providers, without looking like a port scan
+	u64s.insert(U64Setting::MaxSubnetScans, 8);
+	u64s.insert(U64Setting::MaxAsnScans, 200);
+	u64s.insert(U64Setting::ScanRoundSecs, 15);
+	u64s.insert(U64Setting::MaxScansPerSecPerState, 1000);
+	u64s.insert(U64Setting::RequestBlockDepth, 216);
+	u64s.insert(U64Setting::MaxHeightAhead, 1008 * 2);
+	// Nodes which keep failing back off to 8 times the usual retry time, but we always want to
+	// know promptly if a Good or WasGood node goes down
+	for i in 0..AddressState::get_count() {
+		let state = AddressState::from_num(i).unwrap();
+		let backoff = if state == AddressState::Good || state == AddressState::WasGood { 1 } else { 8 };
+		u64s.insert(U64Setting::MaxRescanInterval(state), u64s[&U64Setting::RescanInterval(state)] * backoff);
+	}
+	(u64s, Regex::new(".*").unwrap(), network.dns_seeds.iter().map(|s| s.to_string()).collect())
+}
+
+/// Parses a settings file, returning the settings and any warnings. Settings are key=value lines
+/// (with # comments), and missing settings take their defaults. Settings files from older
+/// versions, which had one value per line in a fixed order, are migrated.
+fn parse_settings(contents: &str, network: &NetworkParams) -> Result<(Settings, Vec<String>), String> {
+	let first_line = contents.lines().map(|l| l.trim()).find(|l| !l.is_empty() && !l.starts_with('#'));
+	if first_line.map(|l| !l.contains('=')).unwrap_or(false) {
+		let settings = parse_positional_settings(contents, network)?;
+		return Ok((settings, vec!["Migrated the settings file to the key=value format".to_string()]));
+	}
+
+	let (mut u64s, mut regex, mut dns_seeds) = default_settings(network);
+	let mut warnings = Vec::new();
+	let u64_settings = U64Setting::all();
+	for (idx, line) in contents.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') { continue; }
+		let (key, value) = line.split_once('=').ok_or_else(|| format!("settings line {}: expected key=value", idx + 1))?;
+		let (key, value) = (key.trim(), value.trim());
+		match key {
+			"subver_regex" => regex = Regex::new(value).map_err(|e| format!("settings line {}: bad subver_regex: {}", idx + 1, e))?,
+			"dns_seeds" => dns_seeds = value.split_whitespace().map(|s| s.to_string()).collect(),
+			_ => match u64_settings.iter().find(|setting| setting.key() == key) {
+				Some(setting) => {
+					let value = value.parse().map_err(|_| format!("settings line {}: bad value for {}: {}", idx + 1, key, value))?;
+					setting.validate(value).map_err(|e| format!("settings line {}: {}", idx + 1, e))?;
+					u64s.insert(*setting, value);
+				},
+				None => warnings.push(format!("Ignoring unknown setting {} on settings line {}", key, idx + 1)),
+			},
 		}
 	}
+	Ok(((u64s, regex, dns_seeds), warnings))
+}
+
+/// Parses the settings file format we used to write: the u64 settings (other than
+/// AllowedDnsServices) and then the subversion regex, one per line, optionally followed by the
+/// allowed DNS services and the upstream DNS seeds.
+fn parse_positional_settings(contents: &str, network: &NetworkParams) -> Result<Settings, String> {
+	let (mut u64s, _, mut dns_seeds) = default_settings(network);
+	let mut lines = contents.lines().enumerate();
+	let mut next_line = |name: &str| lines.next().ok_or_else(|| format!("settings file ends before {}", name));
+	// Positional files predate all the settings after the rescan intervals
+	for setting in U64Setting::all().iter().take_while(|s| **s != U64Setting::AllowedDnsServices) {
+		let (idx, line) = next_line(&setting.key())?;
+		let value = line.parse().map_err(|_| format!("settings line {}: bad value for {}: {}", idx + 1, setting.key(), line))?;
+		setting.validate(value).map_err(|e| format!("settings line {}: {}", idx + 1, e))?;
+		u64s.insert(*setting, value);
+	}
+	let (idx, line) = next_line("subver_regex")?;
+	let regex = Regex::new(line).map_err(|e| format!("settings line {}: bad subver_regex: {}", idx + 1, e))?;
+	if let Ok((idx, line)) = next_line("") {
+		let value = line.parse().map_err(|_| format!("settings line {}: bad value for allowed_dns_services: {}", idx + 1, line))?;
+		u64s.insert(U64Setting::AllowedDnsServices, value);
+	}
+	if let Ok((_, line)) = next_line("") {
+		dns_seeds = line.split_whitespace().map(|s| s.to_string()).collect();
+	}
+	Ok((u64s, regex, dns_seeds))
+}
+
+fn serialize_settings((u64s, regex, dns_seeds): &Settings) -> String {
+	let mut res = "# dnsseed-rust settings, in key=value format. Missing settings take their defaults.\n".to_string();
+	for setting in U64Setting::all() {
+		res += &format!("{}={}\n", setting.key(), u64s[&setting]);
+	}
+	res += &format!("subver_regex={}\n", regex.as_str());
+	res += &format!("dns_seeds={}\n", dns_seeds.join(" "));
+	res
+}
+
+#[test]
+fn test_zone_service_masks() {
+	let masks = zone_service_masks(DEFAULT_ALLOWED_DNS_SERVICES);
+	// The 48 combinations with NETWORK or NETWORK_LIMITED, plus x4, x8 and xc
+	assert_eq!(masks.len(), 51);
+	assert_eq!(masks[..3], [1, 4, 5]);
+	for mask in DNS_SERVICE_MASKS.iter().chain([0x409, 0x809, 0x849, 0xc4d].iter()) {
+		assert!(masks.contains(mask));
+	}
+	assert_eq!(zone_service_masks(0), Vec::<u64>::new());
+	assert_eq!(zone_service_masks(0x8), vec![0x8]);
+	assert_eq!(zone_service_masks(0x9), vec![0x1, 0x8, 0x9]);
+	// Only the lowest MAX_ZONE_SERVICE_BITS allowed bits are combined, on top of DNS_SERVICE_MASKS
+	let masks = zone_service_masks(u64::MAX);
+	assert_eq!(masks.len(), (1 << 7) + 12);
+	assert!(masks.contains(&0x448) && !masks.contains(&0x449));
+}
+
+#[test]
+fn test_settings() {
+	let network = NetworkParams::from_name("bitcoin", None).unwrap();
+	let (defaults, _, default_seeds) = default_settings(&network);
+
+	// Older positional files are migrated, with or without the later optional lines
+	let mut positional: Vec<String> = vec!["60".to_string(), "7200".to_string(), "70001".to_string()];
+	positional.extend((0..AddressState::get_count()).map(|i| (1000 + i as u64).to_string()));
+	positional.push("^/Satoshi:".to_string());
+	let ((u64s, regex, seeds), warnings) = parse_settings(&positional.join("\n"), &network).unwrap();
+	assert_eq!(warnings.len(), 1);
+	assert_eq!(u64s[&U64Setting::RunTimeout], 60);
+	assert_eq!(u64s[&U64Setting::RescanInterval(AddressState::LowBlockCount)], 1001);
+	assert_eq!(u64s[&U64Setting::AllowedDnsServices], DEFAULT_ALLOWED_DNS_SERVICES);
+	assert_eq!(regex.as_str(), "^/Satoshi:");
+	assert_eq!(seeds, default_seeds);
+	positional.push("1".to_string());
+	positional.push("a.example.com b.example.com".to_string());
+	let ((u64s, regex, seeds), _) = parse_settings(&positional.join("\n"), &network).unwrap();
+	assert_eq!(u64s[&U64Setting::AllowedDnsServices], 1);
+	assert_eq!(seeds, vec!["a.example.com", "b.example.com"]);
+	assert!(parse_settings(&positional[..10].join("\n"), &network).is_err());
+
+	// Which then round-trip through the keyed format
+	let keyed = serialize_settings(&(u64s, regex, seeds));
+	assert!(keyed.contains("\nrescan_interval.low_block_count=1001\n"));
+	let ((u64s, regex, seeds), warnings) = parse_settings(&keyed, &network).unwrap();
+	assert!(warnings.is_empty());
+	assert_eq!(u64s[&U64Setting::RescanInterval(AddressState::EvilNode)], 1014);
+	assert_eq!(regex.as_str(), "^/Satoshi:");
+	assert_eq!(seeds, vec!["a.example.com", "b.example.com"]);
+
+	// Missing keys default, unknown keys warn and bad values fail with the line number
+	let ((u64s, regex, _), warnings) = parse_settings("# comment\n\nrun_timeout = 30\nfrobnicate=1\nsubver_regex=a=b\n", &network).unwrap();
+	assert_eq!(u64s[&U64Setting::RunTimeout], 30);
+	assert_eq!(u64s[&U64Setting::WasGoodTimeout], defaults[&U64Setting::WasGoodTimeout]);
+	assert_eq!(regex.as_str(), "a=b");
+	assert_eq!(warnings, vec!["Ignoring unknown setting frobnicate on settings line 4"]);
+	assert_eq!(parse_settings("run_timeout=1\nwas_good_timeout=x\n", &network).err().unwrap(),
+		"settings line 2: bad value for was_good_timeout: x");
+	assert!(parse_settings("run_timeout=1\nsubver_regex=(\n", &network).is_err());
+	assert!(parse_settings("run_timeout=1\nnonsense\n", &network).is_err());
+	assert_eq!(parse_settings("request_block_depth=289\n", &network).err().unwrap(),
+		"settings line 1: request_block_depth must be between 1 and 288");
+	assert!(parse_settings("scan_round_secs=0\n", &network).is_err());
+	assert!(parse_settings("run_timeout=0\n", &network).is_err());
+	assert!(parse_settings("was_good_timeout=4294967296\n", &network).is_err());
+	assert!(parse_settings("was_good_timeout=4294967295\n", &network).is_ok());
+	let ((u64s, _, _), _) = parse_settings("scan_round_secs=30\nmax_height_ahead=0\n", &network).unwrap();
+	assert_eq!(u64s[&U64Setting::ScanRoundSecs], 30);
+	assert_eq!(u64s[&U64Setting::MaxHeightAhead], 0);
+	assert_eq!(u64s[&U64Setting::RequestBlockDepth], 216);
 }
 
 pub struct Store {
 	u64_settings: RwLock<HashMap<U64Setting, u64>>,
+	reachable_networks: RwLock<[bool; NetworkType::get_count() as usize]>,
 	subver_regex: RwLock<Arc<Regex>>,
+	dns_seeds: RwLock<Vec<String>>,
+	/// Stats for every upstream DNS seed we've queried, including ones since removed from
+	/// dns_seeds. Entries are never removed as Node::dns_seed indexes into this.
+	dns_seed_stats: RwLock<Vec<(String, DnsSeedStats)>>,
 	nodes: RwLock<Nodes>,
-	start_time: Instant,
 	store: String,
+	network: &'static NetworkParams,
+	/// If set, we write a complete zone to nodes.zone rather than bare records to nodes.dump
+	zone: Option<ZoneConfig>,
+	/// Whether to publish Good nodes on any port via SRV records
+	publish_srv: AtomicBool,
+	/// Problems loading the datastore which didn't stop us starting
+	load_warnings: Vec<String>,
+	last_bloom_save: Mutex<Instant>,
+	gc_stats: Mutex<GcStats>,
+	connections: Mutex<ConnectionCounts>,
+	dns_candidates: Mutex<DnsCandidatesCache>,
 }
 
 impl Store {
-	pub fn new(store: String) -> impl Future<Item=Store, Error=()> {
+	pub fn new(store: String, network: &'static NetworkParams, zone: Option<ZoneConfig>) -> impl Future<Item=Store, Error=()> {
 		let settings_future = File::open(store.clone() + "/settings").and_then(|f| {
-			let mut l = BufReader::new(f).lines();
-			macro_rules! try_read {
-				($lines: expr, $ty: ty) => { {
-					match $lines.next() {
-						Some(line) => match line {
-							Ok(line) => match line.parse::<$ty>() {
-								Ok(res) => res,
-								Err(e) => return future::err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
-							},
-							Err(e) => return future::err(e),
-						},
-						None => return future::err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "")),
-					}
-				} }
-			}
-			let mut u64s = HashMap::with_capacity(AddressState::get_count() as usize + 4);
-			u64s.insert(U64Setting::RunTimeout, try_read!(l, u64));
-			u64s.insert(U64Setting::WasGoodTimeout, try_read!(l, u64));
-			u64s.insert(U64Setting::MinProtocolVersion, try_read!(l, u64));
-			u64s.insert(U64Setting::RescanInterval(AddressState::Untested), try_read!(l, u64));
-			u64s.insert(U64Setting::RescanInterval(AddressState::LowBlockCount), try_read!(l, u64));
-			u64s.insert(U64Setting::RescanInterval(AddressState::HighBlockCount), try_read!(l, u64));
-			u64s.insert(U64Setting::RescanInterval(AddressState::LowVersion), try_read!(l, u64));
-			u64s.insert(U64Setting::RescanInterval(AddressState::BadVersion), try_read!(l, u64));
-			u64s.insert(U64Setting::RescanInterval(AddressState::NotFullNode), try_read!(l, u64));
-			u64s.insert(U64Setting::RescanInterval(AddressState::ProtocolViolation), try_read!(l, u64));
-			u64s.insert(U64Setting::RescanInterval(AddressState::Timeout), try_read!(l, u64));
-			u64s.insert(U64Setting::RescanInterval(AddressState::TimeoutDuringRequest), try_read!(l, u64));
-			u64s.insert(U64Setting::RescanInterval(AddressState::TimeoutAwaitingPong), try_read!(l, u64));
-			u64s.insert(U64Setting::RescanInterval(AddressState::TimeoutAwaitingAddr), try_read!(l, u64));
-			u64s.insert(U64Setting::RescanInterval(AddressState::TimeoutAwaitingBlock), try_read!(l, u64));
-			u64s.insert(U64Setting::RescanInterval(AddressState::Good), try_read!(l, u64));
-			u64s.insert(U64Setting::RescanInterval(AddressState::WasGood), try_read!(l, u64));
-			u64s.insert(U64Setting::RescanInterval(AddressState::EvilNode), try_read!(l, u64));
-			future::ok((u64s, try_read!(l, Regex)))
-		}).or_else(|_| -> future::FutureResult<(HashMap<U64Setting, u64>, Regex), ()> {
-			let mut u64s = HashMap::with_capacity(15);
-			u64s.insert(U64Setting::RunTimeout, 120);
-			u64s.insert(U64Setting::WasGoodTimeout, 21600);
-			u64s.insert(U64Setting::RescanInterval(AddressState::Untested), 3600);
-			u64s.insert(U64Setting::RescanInterval(AddressState::LowBlockCount), 3600);
-			u64s.insert(U64Setting::RescanInterval(AddressState::HighBlockCount), 7200);
-			u64s.insert(U64Setting::RescanInterval(AddressState::LowVersion), 21600);
-			u64s.insert(U64Setting::RescanInterval(AddressState::BadVersion), 21600);
-			u64s.insert(U64Setting::RescanInterval(AddressState::NotFullNode), 86400);
-			u64s.insert(U64Setting::RescanInterval(AddressState::ProtocolViolation), 86400);
-			u64s.insert(U64Setting::RescanInterval(AddressState::Timeout), 604800);
-			u64s.insert(U64Setting::RescanInterval(AddressState::TimeoutDuringRequest), 21600);
-			u64s.insert(U64Setting::RescanInterval(AddressState::TimeoutAwaitingPong), 3600);
-			u64s.insert(U64Setting::RescanInterval(AddressState::TimeoutAwaitingAddr), 1800);
-			u64s.insert(U64Setting::RescanInterval(AddressState::TimeoutAwaitingBlock), 3600);
-			u64s.insert(U64Setting::RescanInterval(AddressState::Good), 1800);
-			u64s.insert(U64Setting::RescanInterval(AddressState::WasGood), 1800);
-			u64s.insert(U64Setting::RescanInterval(AddressState::EvilNode), 315360000);
-			u64s.insert(U64Setting::MinProtocolVersion, 70002);
-			future::ok((u64s, Regex::new(".*").unwrap()))
+			read_to_end(f, Vec::new())
+		}).then(move |res| match res {
+			Ok((_, data)) => {
+				let contents = String::from_utf8(data).map_err(|_| "settings file isn't valid UTF-8".to_string())?;
+				parse_settings(&contents, network)
+			},
+			Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok((default_settings(network), Vec::new())),
+			Err(e) => Err(e.to_string()),
+		}).map_err(|e| {
+			println!("Failed to load settings: {}", e);
 		});
 
-		macro_rules! nodes_uninitd {
-			() => { {
-				let state_vecs = [Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new()];
-				let good_node_services = [HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new(), HashSet::new()];
-				Nodes {
-					good_node_services,
-					nodes_to_state: HashMap::new(),
-					timeout_nodes: RollingBloomFilter::new(),
-					state_next_scan: state_vecs,
-				}
-			} }
-		}
-
-		let nodes_future = File::open(store.clone() + "/nodes").and_then(|f| {
-			let mut res = nodes_uninitd!();
-			let l = BufReader::new(f).lines();
-			for line_res in l {
-				let line = match line_res {
-					Ok(l) => l,
-					Err(_) => return future::ok(res),
-				};
-				let mut line_iter = line.split(',');
-				macro_rules! try_read {
-					($lines: expr, $ty: ty) => { {
-						match $lines.next() {
-							Some(line) => match line.parse::<$ty>() {
-								Ok(res) => res,
-								Err(_) => return future::ok(res),
-							},
-							None => return future::ok(res),
-						}
-					} }
-				}
-				let sockaddr = try_read!(line_iter, SocketAddr);
-				let state = try_read!(line_iter, u8);
-				let last_services = try_read!(line_iter, u64);
-				let node = Node {
-					state: match AddressState::from_num(state) {
-						Some(v) => v,
-						None => return future::ok(res),
-					},
-					last_services: Node::services(last_services),
-					last_good: 0,
-					queued: true,
-				};
-				if node.state == AddressState::Good {
-					for i in 0..64 {
-						if node.last_services() & (1 << i) != 0 {
-							res.good_node_services[i].insert(sockaddr.into());
-						}
-					}
-				}
-				res.state_next_scan[node.state.to_num() as usize].push(sockaddr.into());
-				res.nodes_to_state.insert(sockaddr.into(), node);
+		let nodes_db_future = File::open(store.clone() + "/nodes.db").and_then(|f| {
+			read_to_end(f, Vec::new())
+		}).and_then(|(_, data)| {
+			Nodes::deserialize(&data)
+		});
+		// Fall back to (and migrate from) the CSV nodes file we used to write
+		let nodes_csv_file = store.clone() + "/nodes";
+		let nodes_future = nodes_db_future.or_else(move |e| {
+			if e.kind() != std::io::ErrorKind::NotFound {
+				return future::Either::A(future::err(e));
+			}
+			future::Either::B(File::open(nodes_csv_file).and_then(|f| {
+				Nodes::from_csv(BufReader::new(f))
+			}).or_else(|e| {
+				if e.kind() == std::io::ErrorKind::NotFound { Ok(Nodes::new()) } else { Err(e) }
+			}))
+		}).map_err(|e| {
+			println!("Failed to load nodes database: {}", e);
+		});
+		// The timeout filter is only a cache of dead addresses, so we can always start afresh
+		// It's read straight into the filter (which is 256MiB) rather than into a buffer first
+		let bloom_file = store.clone() + "/timeout_bloom";
+		let bloom_future = future::lazy(move || -> Result<_, ()> {
+			let res = std::fs::File::open(bloom_file).and_then(|f| RollingBloomFilter::deserialize(&mut BufReader::new(f)));
+			match res {
+				Ok(bloom) => Ok((Some(bloom), None)),
+				Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok((None, None)),
+				Err(ref e) if e.kind() == std::io::ErrorKind::InvalidData || e.kind() == std::io::ErrorKind::UnexpectedEof =>
+					Ok((None, Some("Ignoring corrupt or incompatible timeout_bloom".to_string()))),
+				Err(e) => Ok((None, Some(format!("Failed to read timeout_bloom: {}", e)))),
 			}
-			future::ok(res)
-		}).or_else(|_| -> future::FutureResult<Nodes, ()> {
-			future::ok(nodes_uninitd!())
 		});
-		settings_future.join(nodes_future).and_then(move |((u64_settings, regex), nodes)| {
+		settings_future.join3(nodes_future, bloom_future).and_then(move |(((u64_settings, regex, dns_seeds), mut load_warnings), mut nodes, (bloom, bloom_warning))| {
+			if let Some(bloom) = bloom {
+				nodes.timeout_nodes = bloom;
+			}
+			nodes.schedule_loaded(&u64_settings, unix_time());
+			load_warnings.extend(bloom_warning);
+			let mut reachable_networks = [true; NetworkType::get_count() as usize];
+			// We can only reach I2P nodes once we have a session on a SAM bridge, and CJDNS nodes
+			// once we've been told we have a CJDNS interface
+			reachable_networks[NetworkType::I2P.to_num() as usize] = false;
+			reachable_networks[NetworkType::Cjdns.to_num() as usize] = false;
 			future::ok(Store {
 				u64_settings: RwLock::new(u64_settings),
+				reachable_networks: RwLock::new(reachable_networks),
 				subver_regex: RwLock::new(Arc::new(regex)),
+				dns_seeds: RwLock::new(dns_seeds),
+				dns_seed_stats: RwLock::new(Vec::new()),
 				nodes: RwLock::new(nodes),
 				store,
-				start_time: Instant::now(),
+				network,
+				zone,
+				publish_srv: AtomicBool::new(false),
+				load_warnings,
+				last_bloom_save: Mutex::new(Instant::now()),
+				gc_stats: Mutex::new(GcStats { last_run: None, evicted_for_age: 0, evicted_over_cap: 0 }),
+				connections: Mutex::new(ConnectionCounts::default()),
+				dns_candidates: Mutex::new(HashMap::new()),
 			})
 		})
 	}
 
+	pub fn get_load_warnings(&self) -> &[String] {
+		&self.load_warnings
+	}
+
 	pub fn get_u64(&self, setting: U64Setting) -> u64 {
 		*self.u64_settings.read().unwrap().get(&setting).unwrap()
 	}
 
-	pub fn set_u64(&self, setting: U64Setting, value: u64) {
+	pub fn set_u64(&self, setting: U64Setting, value: u64) -> Result<(), String> {
+		setting.validate(value)?;
 		*self.u64_settings.write().unwrap().get_mut(&setting).unwrap() = value;
+		Ok(())
+	}
+
+	/// Sets whether we can currently connect to nodes on the given network. Nodes on unreachable
+	/// networks aren't returned by get_next_scan_nodes, and are put back for another rescan
+	/// interval instead.
+	pub fn set_network_reachable(&self, network: NetworkType, reachable: bool) {
+		self.reachable_networks.write().unwrap()[network.to_num() as usize] = reachable;
 	}
 
 	pub fn get_node_count(&self, state: AddressState) -> usize {
-		self.nodes.read().unwrap().state_next_scan[state.to_num() as usize].len()
+		self.nodes.read().unwrap().state_node_counts[state.to_num() as usize]
+	}
+	/// Gets the (total, Good) number of nodes we know of on the given network
+	pub fn get_network_node_count(&self, network: NetworkType) -> (usize, usize) {
+		self.nodes.read().unwrap().network_node_counts[network.to_num() as usize]
+	}
+	/// Gets the number of Good nodes which are reliable, and their mean uptime, in each of
+	/// RELIABILITY_WINDOWS
+	pub fn get_reliability_stats(&self) -> [(usize, f32); 5] {
+		let nodes = self.nodes.read().unwrap();
+		let mut res = [(0, 0.0); 5];
+		let mut good_count = 0;
+		for (addr, reliability) in nodes.reliability.iter() {
+			if nodes.nodes_to_state.get(addr).map(|node| node.state) != Some(AddressState::Good) { continue; }
+			good_count += 1;
+			for (window, (count, uptime)) in res.iter_mut().enumerate() {
+				if reliability.is_reliable_in(window) { *count += 1; }
+				*uptime += reliability.uptime(window);
+			}
+		}
+		for (_, uptime) in res.iter_mut() {
+			*uptime /= cmp::max(good_count, 1) as f32;
+		}
+		res
+	}
+	/// Gets the number of nodes evicted for age and over the size cap since startup
+	pub fn get_gc_stats(&self) -> (u64, u64) {
+		let stats = self.gc_stats.lock().unwrap();
+		(stats.evicted_for_age, stats.evicted_over_cap)
+	}
+	pub fn get_connection_counts(&self) -> ConnectionCounts {
+		self.connections.lock().unwrap().clone()
 	}
 	pub fn get_bloom_node_count(&self) -> [usize; crate::bloom::GENERATION_COUNT] {
 		self.nodes.read().unwrap().timeout_nodes.get_element_count()
@@ -387,21 +1937,47 @@ impl Store {
 		*self.subver_regex.write().unwrap() = Arc::new(value);
 	}
 
-	pub fn add_fresh_addrs<I: Iterator<Item=SocketAddr>>(&self, addresses: I) -> u64 {
-		let mut res = 0;
-		let cur_time = (Instant::now() - self.start_time).as_secs().try_into().unwrap();
-		let mut nodes = self.nodes.write().unwrap();
+	/// Gets the upstream DNS seeds we query for new addresses.
+	pub fn get_dns_seeds(&self) -> Vec<String> {
+		self.dns_seeds.read().unwrap().clone()
+	}
+
+	pub fn set_dns_seeds(&self, dns_seeds: Vec<String>) {
+		*self.dns_seeds.write().unwrap() = dns_seeds;
+	}
+
+	/// Gets the stats of every upstream DNS seed we've queried since startup.
+	pub fn get_dns_seed_stats(&self) -> Vec<(String, DnsSeedStats)> {
+		self.dns_seed_stats.read().unwrap().clone()
+	}
+
+	/// Adds any new addresses, marking them as having come from the given 1-indexed DNS seed (or 0
+	/// for none). Returns the number of addresses given and the number which were new.
+	fn add_addrs<I: Iterator<Item=SockAddr>>(&self, addresses: I, dns_seed: u8) -> (u64, u64) {
+		let now = unix_time();
+		let mut res = (0, 0);
+		let mut nodes_lock = self.nodes.write().unwrap();
+		let nodes = nodes_lock.borrow_mut();
 		for addr in addresses {
-			match nodes.nodes_to_state.entry(addr.into()) {
+			res.0 += 1;
+			match nodes.nodes_to_state.entry(addr.clone()) {
 				hash_map::Entry::Vacant(e) => {
 					e.insert(Node {
 						state: AddressState::Untested,
 						last_services: (0, 0),
-						last_good: cur_time,
-						queued: true,
+						last_good: 0,
+						last_seen: 0,
+						first_seen: now,
+						last_attempt: 0,
+						next_scan: 0,
+						dns_seed,
+						failures: 0,
+						history: EMPTY_HISTORY,
 					});
-					nodes.state_next_scan[AddressState::Untested.to_num() as usize].push(addr.into());
-					res += 1;
+					nodes.network_node_counts[addr.network().to_num() as usize].0 += 1;
+					nodes.state_node_counts[AddressState::Untested.to_num() as usize] += 1;
+					schedule_scan(nodes.scan_queue, &addr, nodes.nodes_to_state.get_mut(&addr).unwrap(), now as u64);
+					res.1 += 1;
 				},
 				hash_map::Entry::Occupied(_) => {},
 			}
@@ -409,27 +1985,42 @@ impl Store {
 		res
 	}
 
-	pub fn add_fresh_nodes(&self, addresses: &Vec<(u32, Address)>) {
-		self.add_fresh_addrs(addresses.iter().filter_map(|(_, addr)| {
-			match addr.socket_addr() {
-				Ok(socketaddr) => Some(socketaddr),
-				Err(_) => None, // TODO: Handle onions
+	pub fn add_fresh_addrs<I: Iterator<Item=SockAddr>>(&self, addresses: I) -> u64 {
+		self.add_addrs(addresses, 0).1
+	}
+
+	/// Adds the addresses returned by an upstream DNS seed, tracking them in its stats. Returns the
+	/// number of new addresses.
+	pub fn add_dns_seed_addrs<I: Iterator<Item=SockAddr>>(&self, seed: &str, addresses: I) -> u64 {
+		let idx = {
+			let mut stats = self.dns_seed_stats.write().unwrap();
+			match stats.iter().position(|(name, _)| name == seed) {
+				Some(idx) => idx,
+				None => {
+					stats.push((seed.to_string(), DnsSeedStats::default()));
+					stats.len() - 1
+				},
 			}
-		}));
+		};
+		// We only have room to track the first 255 seeds' nodes, which should be plenty
+		let dns_seed = if idx < 255 { idx as u8 + 1 } else { 0 };
+		let (returned, new) = self.add_addrs(addresses, dns_seed);
+		let stats = &mut self.dns_seed_stats.write().unwrap()[idx].1;
+		stats.returned += returned;
+		stats.new += new;
+		new
+	}
+
+	pub fn add_fresh_nodes(&self, addresses: &Vec<(u32, Address)>) {
+		self.add_fresh_addrs(addresses.iter().map(|(_, addr)| SockAddr::from_addr(addr)));
 	}
 	pub fn add_fresh_nodes_v2(&self, addresses: &Vec<AddrV2Message>) {
-		self.add_fresh_addrs(addresses.iter().filter_map(|addr| {
-			match addr.socket_addr() {
-				Ok(socketaddr) => Some(socketaddr),
-				Err(_) => None, // TODO: Handle onions
-			}
-		}));
+		self.add_fresh_addrs(addresses.iter().filter_map(SockAddr::from_addrv2));
 	}
 
-	pub fn set_node_state(&self, sockaddr: SocketAddr, state: AddressState, services: u64) -> AddressState {
-		let addr: SockAddr = sockaddr.into();
+	pub fn set_node_state(&self, addr: SockAddr, state: AddressState, services: u64) -> AddressState {
 
-		let now = (Instant::now() - self.start_time).as_secs().try_into().unwrap();
+		let now = unix_time();
 
 		let mut nodes_lock = self.nodes.write().unwrap();
 		let nodes = nodes_lock.borrow_mut();
@@ -441,6 +2032,9 @@ impl Store {
 					   entry.get().last_services() == 0 &&
 					   state == AddressState::Timeout => {
 				entry.remove_entry();
+				nodes.details.remove(&addr);
+				nodes.network_node_counts[addr.network().to_num() as usize].0 -= 1;
+				nodes.state_node_counts[AddressState::Untested.to_num() as usize] -= 1;
 				nodes.timeout_nodes.insert(&addr, Duration::from_secs(self.get_u64(U64Setting::RescanInterval(AddressState::Timeout))));
 				return AddressState::Untested;
 			},
@@ -454,28 +2048,45 @@ impl Store {
 			_ => {},
 		}
 
+		let network_counts = &mut nodes.network_node_counts[addr.network().to_num() as usize];
+		if let hash_map::Entry::Vacant(_) = node_entry {
+			network_counts.0 += 1;
+			nodes.state_node_counts[AddressState::Untested.to_num() as usize] += 1;
+		}
 		let state_ref = node_entry.or_insert(Node {
 			state: AddressState::Untested,
 			last_services: (0, 0),
 			last_good: now,
-			queued: false,
+			last_seen: 0,
+			first_seen: now,
+			last_attempt: 0,
+			next_scan: 0,
+			dns_seed: 0,
+			failures: 0,
+			history: EMPTY_HISTORY,
 		});
 		let ret = state_ref.state;
+		if state == AddressState::Good || nodes.reliability.contains_key(&addr) {
+			nodes.reliability.entry(addr.clone()).or_default()
+				.update(state == AddressState::Good, now.saturating_sub(state_ref.last_attempt));
+		}
+		state_ref.last_attempt = now;
+		state_ref.push_history(state);
+		state_ref.failures = if state == AddressState::Good { 0 } else { state_ref.failures.saturating_add(1) };
+		if state != AddressState::Timeout {
+			state_ref.last_seen = now;
+		}
 		let was_good_timeout: u32 = self.get_u64(U64Setting::WasGoodTimeout)
 			.try_into().expect("Need WasGood timeout that fits in a u32");
 		if (state_ref.state == AddressState::Good || state_ref.state == AddressState::WasGood)
 				&& state != AddressState::Good
-				&& state_ref.last_good >= now - was_good_timeout {
+				&& state_ref.last_good >= now.saturating_sub(was_good_timeout) {
 			state_ref.state = AddressState::WasGood;
 			for i in 0..64 {
 				if state_ref.last_services() & (1 << i) != 0 {
 					nodes.good_node_services[i].remove(&addr);
 				}
 			}
-			if !state_ref.queued {
-				nodes.state_next_scan[AddressState::WasGood.to_num() as usize].push(addr);
-				state_ref.queued = true;
-			}
 		} else {
 			state_ref.state = state;
 			if state == AddressState::Good {
@@ -489,37 +2100,128 @@ impl Store {
 				state_ref.last_services = Node::services(services);
 				state_ref.last_good = now;
 			}
-			if !state_ref.queued {
-				nodes.state_next_scan[state.to_num() as usize].push(addr);
-				state_ref.queued = true;
+		}
+		// Rescan once the interval for the new state has passed since this scan, whether or not
+		// the node was already queued (eg if this was a manual scan)
+		let interval = rescan_interval(&self.u64_settings.read().unwrap(), state_ref);
+		schedule_scan(nodes.scan_queue, &addr, state_ref, now as u64 + interval);
+		if ret != state_ref.state {
+			nodes.state_node_counts[ret.to_num() as usize] -= 1;
+			nodes.state_node_counts[state_ref.state.to_num() as usize] += 1;
+		}
+		if ret == AddressState::Good && state_ref.state != AddressState::Good {
+			network_counts.1 -= 1;
+		} else if ret != AddressState::Good && state_ref.state == AddressState::Good {
+			network_counts.1 += 1;
+			if state_ref.dns_seed != 0 {
+				self.dns_seed_stats.write().unwrap()[state_ref.dns_seed as usize - 1].1.good += 1;
+				state_ref.dns_seed = 0;
 			}
 		}
 		ret
 	}
 
+	/// Records what we learned in a scan of a node, after its state has been set. Nodes which have
+	/// never sent us a version message only get a history, not details.
+	pub fn record_scan_details(&self, addr: &SockAddr, version: Option<VersionInfo>, rtt: Option<Duration>, message: String) {
+		let mut nodes_lock = self.nodes.write().unwrap();
+		let nodes = nodes_lock.borrow_mut();
+		if !nodes.nodes_to_state.contains_key(addr) { return; }
+		let details = match (nodes.details.entry(addr.clone()), version.is_some()) {
+			(hash_map::Entry::Occupied(e), _) => e.into_mut(),
+			(hash_map::Entry::Vacant(e), true) => e.insert(NodeDetails { version: None, version_time: 0, rtt_ms: None, last_message: String::new() }),
+			(hash_map::Entry::Vacant(_), false) => return,
+		};
+		if version.is_some() {
+			details.version = version;
+			details.version_time = unix_time();
+		}
+		details.rtt_ms = rtt.map(|rtt| rtt.as_millis().try_into().unwrap_or(u32::MAX));
+		details.last_message = message;
+	}
+
+	fn node_info(nodes: &Nodes, addr: &SockAddr, node: &Node) -> NodeInfo {
+		NodeInfo {
+			state: node.state,
+			services: node.last_services(),
+			last_good: node.last_good,
+			first_seen: node.first_seen,
+			last_seen: node.last_seen,
+			last_attempt: node.last_attempt,
+			failures: node.failures,
+			reliability: nodes.reliability.get(addr).cloned(),
+			history: node.history(),
+			details: nodes.details.get(addr).cloned(),
+		}
+	}
+
+	/// Gets everything we know about a node, if it's a node we know of, and whether it's in the
+	/// filter of addresses which timed out before ever responding.
+	pub fn get_node_info(&self, addr: &SockAddr) -> (Option<NodeInfo>, bool) {
+		let nodes = self.nodes.read().unwrap();
+		let in_timeout_filter = nodes.timeout_nodes.contains(addr);
+		(nodes.nodes_to_state.get(addr).map(|node| Store::node_info(&nodes, addr, node)), in_timeout_filter)
+	}
+
+	/// Gets when a node is next due to be scanned and roughly how many nodes in the same state are
+	/// due before it, or None if it isn't queued (ie it's being scanned right now).
+	pub fn get_next_scan(&self, addr: &SockAddr) -> Option<(u32, usize)> {
+		let nodes = self.nodes.read().unwrap();
+		let node = nodes.nodes_to_state.get(addr)?;
+		if node.next_scan == 0 { return None; }
+		let queue = &nodes.scan_queue.0[node.state.to_num() as usize];
+		let earlier: usize = queue.range(..node.next_scan).map(|(_, due_nodes)| due_nodes.len()).sum();
+		// Nodes due at the same time are taken from the back
+		let later = queue.get(&node.next_scan).and_then(|due_nodes| due_nodes.iter().position(|queued| queued == addr))
+			.map(|position| queue[&node.next_scan].len() - position - 1).unwrap_or(0);
+		Some((node.next_scan, earlier + later))
+	}
+
+	/// Gets roughly the number of nodes which are due to be scanned, including those we'll scan
+	/// this round. Nodes which have been moved or evicted since they were queued are counted until
+	/// they come up.
+	pub fn get_overdue_count(&self) -> usize {
+		let now = unix_time();
+		self.nodes.read().unwrap().scan_queue.0.iter()
+			.map(|queue| queue.range(..=now).map(|(_, due_nodes)| due_nodes.len()).sum::<usize>()).sum()
+	}
+
+	/// Gets the records of all nodes in the given state (or any state) which have (or, if they
+	/// aren't Good, last had) all of the given services.
+	pub fn get_nodes(&self, state: Option<AddressState>, services: u64) -> Vec<(SockAddr, NodeInfo)> {
+		let nodes = self.nodes.read().unwrap();
+		nodes.nodes_to_state.iter()
+			.filter(|(_, node)| state.map(|state| node.state == state).unwrap_or(true) && node.last_services() & services == services)
+			.map(|(addr, node)| (addr.clone(), Store::node_info(&nodes, addr, node)))
+			.collect()
+	}
+
+	pub fn get_network(&self) -> &'static NetworkParams {
+		self.network
+	}
+
+	/// Evicts stale nodes and enforces the node cap, at most once every GC_INTERVAL. Returns the
+	/// number of nodes evicted (for age and over the cap) if we ran.
+	pub fn gc_nodes(&self) -> Option<(usize, usize)> {
+		{
+			let mut stats = self.gc_stats.lock().unwrap();
+			if stats.last_run.map(|last_run| last_run.elapsed() < GC_INTERVAL).unwrap_or(false) { return None; }
+			stats.last_run = Some(Instant::now());
+		}
+		let max_age = self.get_u64(U64Setting::NodeMaxAge).try_into().unwrap_or(u32::MAX);
+		let max_nodes = self.get_u64(U64Setting::MaxNodes).try_into().unwrap_or(usize::MAX);
+		let res = self.nodes.write().unwrap().gc(unix_time(), max_age, max_nodes);
+		let mut stats = self.gc_stats.lock().unwrap();
+		stats.evicted_for_age += res.0 as u64;
+		stats.evicted_over_cap += res.1 as u64;
+		Some(res)
+	}
+
 	pub fn save_data(&'static self) -> impl Future<Item=(), Error=()> {
 		let settings_file = self.store.clone() + "/settings";
 		let settings_future = File::create(settings_file.clone() + ".tmp").and_then(move |f| {
-			let settings_string = format!("{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
-				self.get_u64(U64Setting::RunTimeout),
-				self.get_u64(U64Setting::WasGoodTimeout),
-				self.get_u64(U64Setting::MinProtocolVersion),
-				self.get_u64(U64Setting::RescanInterval(AddressState::Untested)),
-				self.get_u64(U64Setting::RescanInterval(AddressState::LowBlockCount)),
-				self.get_u64(U64Setting::RescanInterval(AddressState::HighBlockCount)),
-				self.get_u64(U64Setting::RescanInterval(AddressState::LowVersion)),
-				self.get_u64(U64Setting::RescanInterval(AddressState::BadVersion)),
-				self.get_u64(U64Setting::RescanInterval(AddressState::NotFullNode)),
-				self.get_u64(U64Setting::RescanInterval(AddressState::ProtocolViolation)),
-				self.get_u64(U64Setting::RescanInterval(AddressState::Timeout)),
-				self.get_u64(U64Setting::RescanInterval(AddressState::TimeoutDuringRequest)),
-				self.get_u64(U64Setting::RescanInterval(AddressState::TimeoutAwaitingPong)),
-				self.get_u64(U64Setting::RescanInterval(AddressState::TimeoutAwaitingAddr)),
-				self.get_u64(U64Setting::RescanInterval(AddressState::TimeoutAwaitingBlock)),
-				self.get_u64(U64Setting::RescanInterval(AddressState::Good)),
-				self.get_u64(U64Setting::RescanInterval(AddressState::WasGood)),
-				self.get_u64(U64Setting::RescanInterval(AddressState::EvilNode)),
-				self.get_regex(RegexSetting::SubverRegex).as_str());
+			let settings_string = serialize_settings(&(self.u64_settings.read().unwrap().clone(),
+				(*self.get_regex(RegexSetting::SubverRegex)).clone(), self.get_dns_seeds()));
 			write_all(f, settings_string).and_then(|(mut f, _)| {
 				f.poll_sync_all()
 			}).and_then(|_| {
@@ -527,21 +2229,9 @@ impl Store {
 			})
 		});
 
-		let nodes_file = self.store.clone() + "/nodes";
+		let nodes_file = self.store.clone() + "/nodes.db";
 		let nodes_future = File::create(nodes_file.clone() + ".tmp").and_then(move |f| {
-			let mut nodes_buff = String::new();
-			{
-				let nodes = self.nodes.read().unwrap();
-				nodes_buff.reserve(nodes.nodes_to_state.len() * 32);
-				for (ref sockaddr, ref node) in nodes.nodes_to_state.iter() {
-					nodes_buff += &sockaddr.to_string();
-					nodes_buff += ",";
-					nodes_buff += &node.state.to_num().to_string();
-					nodes_buff += ",";
-					nodes_buff += &node.last_services().to_string();
-					nodes_buff += "\n";
-				}
-			}
+			let nodes_buff = self.nodes.read().unwrap().serialize();
 			write_all(f, nodes_buff)
 		}).and_then(|(mut f, _)| {
 			f.poll_sync_all()
@@ -549,121 +2239,176 @@ impl Store {
 			tokio::fs::rename(nodes_file.clone() + ".tmp", nodes_file)
 		});
 
-		settings_future.join(nodes_future).then(|_| { future::ok(()) })
+		// The timeout filter is large, so we only save it every so often, and when shutting down
+		let bloom_future = {
+			let mut last_bloom_save = self.last_bloom_save.lock().unwrap();
+			if START_SHUTDOWN.load(Ordering::Relaxed) || last_bloom_save.elapsed() > BLOOM_SAVE_INTERVAL {
+				*last_bloom_save = Instant::now();
+				let bloom_file = self.store.clone() + "/timeout_bloom";
+				future::Either::A(File::create(bloom_file.clone() + ".tmp").and_then(move |f| {
+					let bloom_buff = self.nodes.read().unwrap().timeout_nodes.serialize();
+					write_all(f, bloom_buff)
+				}).and_then(|(mut f, _)| {
+					f.poll_sync_all()
+				}).and_then(|_| {
+					tokio::fs::rename(bloom_file.clone() + ".tmp", bloom_file)
+				}))
+			} else {
+				future::Either::B(future::ok(()))
+			}
+		};
+
+		settings_future.join3(nodes_future, bloom_future).then(|_| { future::ok(()) })
 	}
 
+	/// Gets the IP addresses of all Good nodes with all of the given service bits set (and, unless
+	/// any_port is set, on the default port), along with the network each is on and whether it's
+	/// reliable. Tor v3 and I2P nodes don't have IPs and are never included.
+	fn get_good_dns_addrs(&self, services: u64, any_port: bool) -> Vec<(NetworkType, SocketAddr, bool)> {
+		let mut res = Vec::new();
+		if services == 0 { return res; }
+		let nodes = self.nodes.read().unwrap();
+		let first_bit = services.trailing_zeros() as usize;
+		for a in nodes.good_node_services[first_bit].iter().filter(|e| any_port || e.port() == self.network.default_port) {
+			if (first_bit + 1..64).any(|j| services & (1 << j) != 0 && !nodes.good_node_services[j].contains(a)) {
+				continue;
+			}
+			if let Some(ip) = a.ip() {
+				let reliable = nodes.reliability.get(a).map(|r| r.is_reliable()).unwrap_or(false);
+				res.push((a.network(), SocketAddr::new(ip, a.port()), reliable));
+			}
+		}
+		res
+	}
+
+	/// Gets the DnsCandidates for the given services (and ports, as for get_good_dns_addrs),
+	/// refreshing them if they're more than DNS_CANDIDATES_REFRESH old.
+	fn get_dns_candidates(&self, services: u64, any_port: bool) -> Arc<DnsCandidates> {
+		let mut cache = self.dns_candidates.lock().unwrap();
+		if let Some((refreshed, candidates)) = cache.get(&(services, any_port)) {
+			if refreshed.elapsed() < DNS_CANDIDATES_REFRESH { return Arc::clone(candidates); }
+		}
+		let candidates = Arc::new(DnsCandidates::new(self.get_good_dns_addrs(services, any_port)));
+		cache.retain(|_, (refreshed, _)| refreshed.elapsed() < DNS_CANDIDATES_REFRESH);
+		cache.insert((services, any_port), (Instant::now(), Arc::clone(&candidates)));
+		candidates
+	}
+
+	/// Selects a random set of Good nodes on the default port with all of the given service bits
+	/// set, for use in DNS responses, preferring reliable nodes. At most one v4 and one v6 node is
+	/// picked per ASN (and nodes for which we have no ASN are skipped entirely). Tor nodes are
+	/// appended to the v6 set. CJDNS nodes are only reachable by other CJDNS nodes, so aren't
+	/// included (see get_cjdns_dns_nodes).
+	pub fn get_dns_nodes(&self, services: u64, bgp_client: &BGPClient) -> (Vec<Ipv4Addr>, Vec<Ipv6Addr>) {
+		let candidates = self.get_dns_candidates(services, false);
+		// Walk the shuffled candidates, taking the first node we see in each ASN. This avoids
+		// looking up the ASN of every Good node on each DNS query.
+		let mut asn_set = HashSet::with_capacity(21);
+		asn_set.insert(0);
+		let v4_res: Vec<Ipv4Addr> = candidates.sample(NetworkType::IPv4).filter_map(|a| match a.ip() {
+			IpAddr::V4(v4addr) => Some(v4addr),
+			IpAddr::V6(_) => None,
+		}).filter(|a| asn_set.insert(bgp_client.get_asn(IpAddr::V4(*a)))).take(21).collect();
+		asn_set.clear();
+		asn_set.insert(0);
+		let v6_ips = |network| candidates.sample(network).filter_map(|a| match a.ip() {
+			IpAddr::V6(v6addr) => Some(v6addr),
+			IpAddr::V4(_) => None,
+		});
+		let mut v6_res: Vec<Ipv6Addr> = v6_ips(NetworkType::IPv6)
+			.filter(|a| asn_set.insert(bgp_client.get_asn(IpAddr::V6(*a)))).take(10).collect();
+		v6_res.extend(v6_ips(NetworkType::TorV2).take(2));
+		(v4_res, v6_res)
+	}
+
+	/// Selects a random set of Good CJDNS nodes on the default port with all of the given service
+	/// bits set. CJDNS addresses aren't announced over BGP, so there's no ASN diversity to enforce.
+	pub fn get_cjdns_dns_nodes(&self, services: u64) -> Vec<Ipv6Addr> {
+		self.get_dns_candidates(services, false).sample(NetworkType::Cjdns).filter_map(|a| match a.ip() {
+			IpAddr::V6(v6addr) => Some(v6addr),
+			IpAddr::V4(_) => None,
+		}).take(10).collect()
+	}
+
+	/// Selects a random set of Good IPv4 and IPv6 nodes on any port with all of the given service
+	/// bits set, for publishing in SRV records. As with get_dns_nodes, reliable nodes are preferred
+	/// and at most one node is picked per ASN for each of v4 and v6.
+	pub fn get_srv_nodes(&self, services: u64, bgp_client: &BGPClient) -> Vec<SocketAddr> {
+		let candidates = self.get_dns_candidates(services, true);
+		let mut asn_set = HashSet::with_capacity(10);
+		asn_set.insert(0);
+		let mut res: Vec<SocketAddr> = candidates.sample(NetworkType::IPv4)
+			.filter(|a| asn_set.insert(bgp_client.get_asn(a.ip()))).take(10).collect();
+		asn_set.clear();
+		asn_set.insert(0);
+		res.extend(candidates.sample(NetworkType::IPv6).filter(|a| asn_set.insert(bgp_client.get_asn(a.ip()))).take(10));
+		res
+	}
+
+	/// Whether we currently consider the given node Good.
+	pub fn is_good(&self, addr: &SockAddr) -> bool {
+		self.nodes.read().unwrap().nodes_to_state.get(addr).map(|node| node.state == AddressState::Good).unwrap_or(false)
+	}
+
+	/// Sets whether we publish SRV records (for Good nodes on any port) alongside A/AAAA records.
+	pub fn set_publish_srv(&self, publish_srv: bool) {
+		self.publish_srv.store(publish_srv, Ordering::Relaxed);
+	}
+
+	pub fn get_publish_srv(&self) -> bool {
+		self.publish_srv.load(Ordering::Relaxed)
+	}
+
+	/// Writes A/AAAA records for the seed and its service bit subdomains, either as bare records
+	/// (to nodes.dump) or, if we have a ZoneConfig, as a complete zone (to nodes.zone).
 	pub fn write_dns(&'static self, bgp_client: Arc<BGPClient>) -> impl Future<Item=(), Error=()> {
-		let dns_file = self.store.clone() + "/nodes.dump";
+		let dns_file = self.store.clone() + if self.zone.is_some() { "/nodes.zone" } else { "/nodes.dump" };
 		File::create(dns_file.clone() + ".tmp").and_then(move |f| {
-			let mut dns_buff = String::new();
-			{
-				let mut rng = thread_rng();
-				for i in &[ 0b00000000001u64,
-				            0b00000000100,
-				            0b00000000101,
-				            0b00000001000,
-				            0b00000001001,
-				            0b00000001100,
-				            0b00000001101,
-				            0b00001001001,
-				            0b10000000000,
-				            0b10000000001,
-				            0b10000000100,
-				            0b10000000101,
-				            0b10000001000,
-				            0b10000001001,
-				            0b10000001100,
-				            0b10000001101,
-				            0b10001001000] {
-				//            ^ NODE_NETWORK_LIIMTED
-				//COMPACT_FILTERS ^   ^ NODE_BLOOM
-				//      NODE_WITNESS ^  ^ NODE_NETWORK
-				// We support all combos of NETWORK, NETWORK_LIMITED, BLOOM, and WITNESS
-				// We support COMPACT_FILTERS with WITNESS and NETWORK or NETWORK_LIIMTED.
-					let mut tor_set: Vec<Ipv6Addr> = Vec::new();
-					let mut v6_set: Vec<Ipv6Addr> = Vec::new();
-					let mut v4_set: Vec<Ipv4Addr> = Vec::new();
-					macro_rules! add_addr { ($addr: expr) => {
-						match $addr.ip() {
-							IpAddr::V4(v4addr) => v4_set.push(v4addr),
-							IpAddr::V6(v6addr) if v6addr.octets()[..6] == [0xFD,0x87,0xD8,0x7E,0xEB,0x43][..] => tor_set.push(v6addr),
-							IpAddr::V6(v6addr) => v6_set.push(v6addr),
+			let (seed_label, service_prefix) = match self.zone {
+				Some(ref zone) => (zone.seed_label(), zone.service_prefix()),
+				None => (self.network.zone_name, "x"),
+			};
+			let cjdns_label = sub_label("cjdns", seed_label);
+			let publish_srv = self.get_publish_srv();
+			let mut records: Vec<(String, RecordData)> = Vec::new();
+			let mut srv_targets = HashSet::new();
+			let allowed_services = self.get_u64(U64Setting::AllowedDnsServices);
+			for i in zone_service_masks(allowed_services).iter() {
+				let service_label = format!("{}{:x}", service_prefix, i);
+				let mut add_records = |label: &str, addrs: Vec<IpAddr>| {
+					for a in addrs {
+						if *i == DEFAULT_DNS_SERVICES {
+							records.push((label.to_string(), RecordData::Addr(a)));
 						}
-					} }
-					{
-						let nodes = self.nodes.read().unwrap();
-						if i.count_ones() == 1 {
-							for j in 0..64 {
-								if i & (1 << j) != 0 {
-									let set_ref = &nodes.good_node_services[j];
-									for a in set_ref.iter().filter(|e| e.port() == 8333) {
-										add_addr!(a);
-									}
-									break;
-								}
-							}
-						} else if i.count_ones() == 2 {
-							let mut first_set = None;
-							let mut second_set = None;
-							for j in 0..64 {
-								if i & (1 << j) != 0 {
-									if first_set == None {
-										first_set = Some(&nodes.good_node_services[j]);
-									} else {
-										second_set = Some(&nodes.good_node_services[j]);
-										break;
-									}
-								}
-							}
-							for a in first_set.unwrap().intersection(&second_set.unwrap()).filter(|e| e.port() == 8333) {
-								add_addr!(a);
-							}
-						} else {
-							//TODO: Could optimize this one a bit
-							let mut intersection;
-							let mut intersection_set_ref = None;
-							for j in 0..64 {
-								if i & (1 << j) != 0 {
-									if intersection_set_ref == None {
-										intersection_set_ref = Some(&nodes.good_node_services[j]);
-									} else {
-										let new_intersection = intersection_set_ref.unwrap()
-											.intersection(&nodes.good_node_services[j]).map(|e| (*e).clone()).collect();
-										intersection = Some(new_intersection);
-										intersection_set_ref = Some(intersection.as_ref().unwrap());
-									}
-								}
-							}
-							for a in intersection_set_ref.unwrap().iter().filter(|e| e.port() == 8333) {
-								add_addr!(a);
-							}
-						}
-					}
-					let mut asn_set = HashSet::with_capacity(cmp::max(v4_set.len(), v6_set.len()));
-					asn_set.insert(0);
-					let default = 1u64;
-					for a in v4_set.iter().filter(|a| asn_set.insert(bgp_client.get_asn(IpAddr::V4(**a)))).choose_multiple(&mut rng, 21) {
-						if i == &default {
-							dns_buff += &format!("seed\tIN\tA\t{}\n", a);
-						}
-						dns_buff += &format!("x{:x}.seed\tIN\tA\t{}\n", i, a);
+						records.push((sub_label(&service_label, label), RecordData::Addr(a)));
 					}
-					asn_set.clear();
-					asn_set.insert(0);
-					for a in v6_set.iter().filter(|a| asn_set.insert(bgp_client.get_asn(IpAddr::V6(**a)))).choose_multiple(&mut rng, 10) {
-						if i == &default {
-							dns_buff += &format!("seed\tIN\tAAAA\t{}\n", a);
-						}
-						dns_buff += &format!("x{:x}.seed\tIN\tAAAA\t{}\n", i, a);
-					}
-					for a in tor_set.iter().choose_multiple(&mut rng, 2) {
-						if i == &default {
-							dns_buff += &format!("seed\tIN\tAAAA\t{}\n", a);
+				};
+				let (v4_set, v6_set) = self.get_dns_nodes(*i, &bgp_client);
+				add_records(seed_label, v4_set.into_iter().map(IpAddr::V4).chain(v6_set.into_iter().map(IpAddr::V6)).collect());
+				add_records(&cjdns_label, self.get_cjdns_dns_nodes(*i).into_iter().map(IpAddr::V6).collect());
+				if publish_srv {
+					// SRV records go under _bitcoin._tcp.<seed> and _bitcoin._tcp.x<hex>.<seed>
+					let srv_nodes = self.get_srv_nodes(*i, &bgp_client);
+					for a in srv_nodes.iter() {
+						let target = sub_label(&srv_target_label(a), seed_label);
+						if *i == DEFAULT_DNS_SERVICES {
+							records.push((sub_label("_bitcoin._tcp", seed_label), RecordData::Srv(a.port(), target.clone())));
 						}
-						dns_buff += &format!("x{:x}.seed\tIN\tAAAA\t{}\n", i, a);
+						records.push((sub_label("_bitcoin._tcp", &sub_label(&service_label, seed_label)), RecordData::Srv(a.port(), target)));
 					}
+					srv_targets.extend(srv_nodes);
 				}
 			}
+			for a in srv_targets {
+				records.push((sub_label(&srv_target_label(&a), seed_label), RecordData::Addr(a.ip())));
+			}
+			let dns_buff = match self.zone {
+				Some(ref zone) => zone.render(&records),
+				None => records.iter().map(|(label, data)| {
+					let (rtype, text) = data.type_text();
+					format!("{}\tIN\t{}\t{}\n", label, type_to_str(rtype), text)
+				}).collect(),
+			};
 			write_all(f, dns_buff)
 		}).and_then(|(mut f, _)| {
 			f.poll_sync_all()
@@ -672,24 +2417,71 @@ impl Store {
 		}).then(|_| { future::ok(()) })
 	}
 
-	pub fn get_next_scan_nodes(&self) -> Vec<SocketAddr> {
-		let mut res = Vec::with_capacity(128);
+	/// Writes all Good nodes on the given network (and their services) to the given file in the
+	/// store, for networks whose addresses can't be published in DNS.
+	pub fn write_good_nodes(&'static self, network: NetworkType, file_name: &str) -> impl Future<Item=(), Error=()> {
+		let nodes_file = self.store.clone() + "/" + file_name;
+		File::create(nodes_file.clone() + ".tmp").and_then(move |f| {
+			let mut nodes_buff = String::new();
+			{
+				let nodes = self.nodes.read().unwrap();
+				for (addr, node) in nodes.nodes_to_state.iter() {
+					if node.state == AddressState::Good && addr.network() == network {
+						nodes_buff += &format!("{}\t{:x}\n", addr, node.last_services());
+					}
+				}
+			}
+			write_all(f, nodes_buff)
+		}).and_then(|(mut f, _)| {
+			f.poll_sync_all()
+		}).and_then(|_| {
+			tokio::fs::rename(nodes_file.clone() + ".tmp", nodes_file)
+		}).then(|_| { future::ok(()) })
+	}
+
+	pub fn get_next_scan_nodes(&self) -> Vec<SockAddr> {
+		let reachable_networks = *self.reachable_networks.read().unwrap();
+		let mut res = {
+			let mut nodes = self.nodes.write().unwrap();
+			let u64_settings = self.u64_settings.read().unwrap();
+			let max_per_state = u64_settings[&U64Setting::ScanRoundSecs].saturating_mul(u64_settings[&U64Setting::MaxScansPerSecPerState]);
+			nodes.take_due_nodes(unix_time(), &reachable_networks, &u64_settings, max_per_state)
+		};
+		res.shuffle(&mut thread_rng());
+		res
+	}
+
+	/// Reserves a connection to addr for a scan, unless that would take us over the overall or the
+	/// network's connection cap. Manual scans always get one. Must be followed by close_connection.
+	pub fn try_open_connection(&self, addr: &SockAddr, manual: bool) -> bool {
+		let u64_settings = self.u64_settings.read().unwrap();
+		self.connections.lock().unwrap().try_open(addr, &u64_settings, manual)
+	}
 
+	pub fn close_connection(&self, addr: &SockAddr) {
+		self.connections.lock().unwrap().close(addr);
+	}
+
+	/// Puts nodes from get_next_scan_nodes which we didn't scan, as we were at a connection cap or
+	/// (if rate_limited is set) to limit connections to a subnet or ASN, back in the scan queue to
+	/// be scanned as soon as possible.
+	pub fn defer_scans(&self, addrs: Vec<SockAddr>, rate_limited: bool) {
+		let now = unix_time();
 		{
 			let mut nodes_lock = self.nodes.write().unwrap();
-			let nodes = nodes_lock.borrow_mut();
-			for (idx, state_nodes) in nodes.state_next_scan.iter_mut().enumerate() {
-				let rescan_interval = cmp::max(self.get_u64(U64Setting::RescanInterval(AddressState::from_num(idx as u8).unwrap())), 1);
-				let split_point = cmp::min(cmp::min(SECS_PER_SCAN_RESULTS * state_nodes.len() as u64 / rescan_interval,
-							SECS_PER_SCAN_RESULTS * MAX_CONNS_PER_SEC_PER_STATUS),
-						state_nodes.len() as u64);
-				for node in state_nodes.drain(..split_point as usize) {
-					nodes.nodes_to_state.get_mut(&node).unwrap().queued = false;
-					res.push((&node).into());
+			let nodes = &mut *nodes_lock;
+			for addr in addrs.iter() {
+				// The node may have been evicted since
+				if let Some(node) = nodes.nodes_to_state.get_mut(addr) {
+					schedule_scan(&mut nodes.scan_queue, addr, node, now as u64);
 				}
 			}
 		}
-		res.shuffle(&mut thread_rng());
-		res
+		let mut counts = self.connections.lock().unwrap();
+		if rate_limited {
+			counts.rate_limited += addrs.len() as u64;
+		} else {
+			counts.deferred += addrs.len() as u64;
+		}
 	}
 }
@@ -0,0 +1,425 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+use tokio::prelude::*;
+use tokio::codec::{BytesCodec, Framed};
+use tokio::codec::length_delimited;
+use tokio::net::{TcpListener, UdpFramed, UdpSocket};
+use tokio::timer::{Delay, Interval};
+
+use crate::bgp_client::BGPClient;
+use crate::datastore::{SockAddr, Store, U64Setting, DEFAULT_DNS_SERVICES};
+use crate::zone::{name_to_wire, parse_srv_target_label, srv_rdata, srv_target_label, ZoneConfig, TYPE_SOA, TYPE_SRV};
+use crate::printer::Printer;
+use crate::timeout_stream::TimeoutStream;
+
+use crate::START_SHUTDOWN;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+
+const RCODE_NOERROR: u16 = 0;
+const RCODE_FORMERR: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+const RCODE_REFUSED: u16 = 5;
+
+/// We don't bother with EDNS, so UDP responses are limited to the classic 512 bytes.
+const MAX_UDP_RESPONSE: usize = 512;
+const MAX_TCP_RESPONSE: usize = 65535;
+const ANSWER_TTL: u32 = 60;
+
+struct Question {
+	labels: Vec<String>,
+	qtype: u16,
+	qclass: u16,
+	/// Length of the header plus the question, ie the part of the request we echo back.
+	len: usize,
+}
+
+/// Parses the (single) question out of a DNS request, returning the rcode to respond with on
+/// failure.
+fn parse_question(req: &[u8]) -> Result<Question, u16> {
+	if req[2] & 0x78 != 0 { return Err(RCODE_NOTIMP); } // Only standard queries
+	if req[4..6] != [0, 1] { return Err(RCODE_FORMERR); }
+
+	let mut labels = Vec::new();
+	let mut pos = 12;
+	loop {
+		if pos >= req.len() { return Err(RCODE_FORMERR); }
+		let len = req[pos] as usize;
+		pos += 1;
+		if len == 0 { break; }
+		// Compression pointers (and the reserved label types) never appear in sane questions
+		if len > 63 || pos + len > req.len() || pos > 12 + 255 { return Err(RCODE_FORMERR); }
+		labels.push(String::from_utf8_lossy(&req[pos..pos + len]).to_ascii_lowercase());
+		pos += len;
+	}
+	if pos + 4 > req.len() { return Err(RCODE_FORMERR); }
+	Ok(Question {
+		labels,
+		qtype: ((req[pos] as u16) << 8) | req[pos + 1] as u16,
+		qclass: ((req[pos + 2] as u16) << 8) | req[pos + 3] as u16,
+		len: pos + 4,
+	})
+}
+
+/// What a queried name under our seed name refers to.
+#[derive(Debug, PartialEq)]
+enum SeedName {
+	/// A/AAAA records for nodes with the given service bits, or for CJDNS nodes if cjdns is set
+	Nodes { services: u64, cjdns: bool },
+	/// SRV records (at _bitcoin._tcp) for nodes with the given service bits, on any port
+	Srv { services: u64 },
+	/// The target of one of our SRV records
+	SrvTarget(SocketAddr),
+}
+
+/// Maps a queried name to what it refers to, given the (lowercased) labels of our seed name and
+/// the service bits clients are allowed to require. Returns the rcode to respond with if the name
+/// isn't one we serve.
+fn services_for_name(seed_name: &[String], labels: &[String], allowed_services: u64) -> Result<SeedName, u16> {
+	if labels.len() < seed_name.len() || labels[labels.len() - seed_name.len()..] != *seed_name {
+		return Err(RCODE_REFUSED);
+	}
+	let labels = &labels[..labels.len() - seed_name.len()];
+	if let [label] = labels {
+		if let Some(addr) = parse_srv_target_label(label) {
+			return Ok(SeedName::SrvTarget(addr));
+		}
+	}
+	let (srv, labels) = match labels {
+		[service, proto, rest @ ..] if service == "_bitcoin" && proto == "_tcp" => (true, rest),
+		labels => (false, labels),
+	};
+	let (cjdns, labels) = match labels {
+		[rest @ .., last] if last == "cjdns" && !srv => (true, rest),
+		labels => (false, labels),
+	};
+	let services = match labels {
+		[] => DEFAULT_DNS_SERVICES,
+		[label] if label.starts_with('x') => {
+			match u64::from_str_radix(&label[1..], 16) {
+				Ok(services) if services != 0 && services & !allowed_services == 0 &&
+					label[1..] == format!("{:x}", services) => services,
+				_ => return Err(RCODE_NXDOMAIN),
+			}
+		},
+		_ => return Err(RCODE_NXDOMAIN),
+	};
+	Ok(if srv { SeedName::Srv { services } } else { SeedName::Nodes { services, cjdns } })
+}
+
+fn push_u16(buf: &mut Vec<u8>, v: u16) {
+	buf.extend_from_slice(&v.to_be_bytes());
+}
+
+/// Appends a record with the given (possibly compressed) name in wire format.
+fn push_record(buf: &mut Vec<u8>, name: &[u8], rtype: u16, rdata: &[u8]) {
+	buf.extend_from_slice(name);
+	push_u16(buf, rtype);
+	push_u16(buf, CLASS_IN);
+	buf.extend_from_slice(&ANSWER_TTL.to_be_bytes());
+	push_u16(buf, rdata.len() as u16);
+	buf.extend_from_slice(rdata);
+}
+
+fn addr_rdata(addr: &SocketAddr) -> (u16, Vec<u8>) {
+	match addr {
+		SocketAddr::V4(v4) => (TYPE_A, v4.ip().octets().to_vec()),
+		SocketAddr::V6(v6) => (TYPE_AAAA, v6.ip().octets().to_vec()),
+	}
+}
+
+pub struct DnsServer {
+	seed_name: Vec<String>,
+	/// Gives the SOA and NS records for the seed name itself
+	zone: ZoneConfig,
+	store: &'static Store,
+	bgp_client: Arc<BGPClient>,
+}
+
+impl DnsServer {
+	/// Builds the response to a single DNS request, or None if we should simply drop it.
+	fn respond(&self, req: &[u8], max_len: usize) -> Option<Vec<u8>> {
+		if req.len() < 12 || req[2] & 0x80 != 0 { return None; }
+
+		let mut resp = Vec::with_capacity(max_len);
+		resp.extend_from_slice(&req[0..2]);
+		// QR + AA, echoing back the opcode and RD bits
+		resp.push(0x80 | 0x04 | (req[2] & 0x79));
+		resp.push(0);
+		resp.extend_from_slice(&[0; 8]);
+
+		let question = match parse_question(req) {
+			Ok(question) => question,
+			Err(rcode) => {
+				resp[3] = rcode as u8;
+				return Some(resp);
+			}
+		};
+		resp[5] = 1; // QDCOUNT
+		resp.extend_from_slice(&req[12..question.len]);
+		if resp.len() > max_len { return None; }
+
+		if question.qclass != CLASS_IN && question.qclass != CLASS_ANY {
+			resp[3] = RCODE_REFUSED as u8;
+			return Some(resp);
+		}
+		let allowed_services = self.store.get_u64(U64Setting::AllowedDnsServices);
+		let name = match services_for_name(&self.seed_name, &question.labels, allowed_services) {
+			Ok(SeedName::Srv { .. }) | Ok(SeedName::SrvTarget(_)) if !self.store.get_publish_srv() => Err(RCODE_NXDOMAIN),
+			// Only answer for nodes we'd (still) publish, so that we aren't an open port-encoding oracle
+			Ok(SeedName::SrvTarget(addr)) if !self.store.is_good(&SockAddr::from(addr)) => Err(RCODE_NXDOMAIN),
+			res => res,
+		};
+		let name = match name {
+			Ok(name) => name,
+			Err(rcode) => {
+				resp[3] = rcode as u8;
+				if rcode == RCODE_NXDOMAIN {
+					self.push_soa_authority(&mut resp, max_len);
+				}
+				return Some(resp);
+			}
+		};
+
+		let mut answers = 0u16;
+		let mut additionals = 0u16;
+		match name {
+			SeedName::Nodes { services, cjdns } => {
+				if question.labels.len() == self.seed_name.len() {
+					answers = self.push_apex_answers(&mut resp, max_len, question.qtype);
+				}
+				answers += self.push_node_answers(&mut resp, max_len, question.qtype, services, cjdns);
+			},
+			SeedName::Srv { services } if question.qtype == TYPE_SRV || question.qtype == TYPE_ANY => {
+				let mut records = Vec::new();
+				let mut len = resp.len();
+				for addr in self.store.get_srv_nodes(services, &self.bgp_client) {
+					// Each SRV record is matched by the target's A/AAAA record in the additional section.
+					// SRV targets may not be compressed, but the additional record's name can point at one.
+					let target = name_to_wire(&format!("{}.{}", srv_target_label(&addr), self.seed_name.join(".")));
+					let (rtype, rdata) = addr_rdata(&addr);
+					let record_len = 12 + 6 + target.len() + 12 + rdata.len();
+					if len + record_len > max_len { break; }
+					len += record_len;
+					records.push((addr.port(), target, rtype, rdata));
+				}
+				let mut target_offsets = Vec::with_capacity(records.len());
+				for (port, target, _, _) in records.iter() {
+					target_offsets.push(resp.len() + 12 + 6);
+					push_record(&mut resp, &[0xc0, 12], TYPE_SRV, &srv_rdata(*port, target));
+					answers += 1;
+				}
+				for ((_, _, rtype, rdata), offset) in records.iter().zip(target_offsets) {
+					push_record(&mut resp, &[0xc0 | (offset >> 8) as u8, offset as u8], *rtype, rdata);
+					additionals += 1;
+				}
+			},
+			SeedName::SrvTarget(addr) => {
+				let (rtype, rdata) = addr_rdata(&addr);
+				if question.qtype == rtype || question.qtype == TYPE_ANY {
+					push_record(&mut resp, &[0xc0, 12], rtype, &rdata);
+					answers += 1;
+				}
+			},
+			SeedName::Srv { .. } => {},
+		}
+		resp[3] = RCODE_NOERROR as u8;
+		resp[6..8].copy_from_slice(&answers.to_be_bytes());
+		resp[10..12].copy_from_slice(&additionals.to_be_bytes());
+		if answers == 0 && additionals == 0 {
+			self.push_soa_authority(&mut resp, max_len);
+		}
+		Some(resp)
+	}
+
+	/// Gets our SOA and NS records. The nodes we return change with every query, so the SOA serial
+	/// is just the current time.
+	fn apex_records(&self) -> Vec<(u16, Vec<u8>)> {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("time > 1970").as_secs() as u32;
+		self.zone.apex_records(now).into_iter().map(|record| (record.rtype, record.rdata)).collect()
+	}
+
+	/// Appends SOA and/or NS answers (per qtype) for a query for the seed name itself, returning
+	/// the number of answers added.
+	fn push_apex_answers(&self, resp: &mut Vec<u8>, max_len: usize, qtype: u16) -> u16 {
+		let mut answers = 0;
+		for (rtype, rdata) in self.apex_records() {
+			if qtype != rtype && qtype != TYPE_ANY { continue; }
+			if resp.len() + 12 + rdata.len() > max_len { break; }
+			push_record(resp, &[0xc0, 12], rtype, &rdata);
+			answers += 1;
+		}
+		answers
+	}
+
+	/// Appends our SOA record to the authority section, as NXDOMAIN and empty NOERROR responses
+	/// should have so that resolvers can cache them.
+	fn push_soa_authority(&self, resp: &mut Vec<u8>, max_len: usize) {
+		let name = name_to_wire(self.zone.origin());
+		if let Some((rtype, rdata)) = self.apex_records().into_iter().find(|(rtype, _)| *rtype == TYPE_SOA) {
+			if resp.len() + name.len() + 10 + rdata.len() > max_len { return; }
+			push_record(resp, &name, rtype, &rdata);
+			resp[8..10].copy_from_slice(&1u16.to_be_bytes());
+		}
+	}
+
+	/// Appends A and/or AAAA answers (per qtype) for a selection of nodes with the given services,
+	/// returning the number of answers added.
+	fn push_node_answers(&self, resp: &mut Vec<u8>, max_len: usize, qtype: u16, services: u64, cjdns: bool) -> u16 {
+		let mut answers = 0;
+		if qtype == TYPE_A || qtype == TYPE_AAAA || qtype == TYPE_ANY {
+			let (v4_addrs, v6_addrs) = if cjdns {
+				(Vec::new(), self.store.get_cjdns_dns_nodes(services))
+			} else {
+				self.store.get_dns_nodes(services, &self.bgp_client)
+			};
+			let want_v4 = qtype != TYPE_AAAA;
+			let want_v6 = qtype != TYPE_A;
+			let v4_rdata = v4_addrs.iter().filter(|_| want_v4).map(|a| (TYPE_A, a.octets().to_vec()));
+			let v6_rdata = v6_addrs.iter().filter(|_| want_v6).map(|a| (TYPE_AAAA, a.octets().to_vec()));
+			for (rtype, rdata) in v4_rdata.chain(v6_rdata) {
+				if resp.len() + 12 + rdata.len() > max_len { break; }
+				push_record(resp, &[0xc0, 12], rtype, &rdata); // Pointer to the name in the question
+				answers += 1;
+			}
+		}
+		answers
+	}
+
+	fn listen_udp(server: Arc<DnsServer>, bind: SocketAddr, printer: &'static Printer) {
+		let socket = match UdpSocket::bind(&bind) {
+			Ok(socket) => socket,
+			Err(e) => {
+				printer.add_line(format!("Failed to bind DNS UDP socket to {}: {:?}", bind, e), true);
+				return;
+			},
+		};
+		let (sink, stream) = UdpFramed::new(socket, BytesCodec::new()).split();
+		let responder = Arc::clone(&server);
+		// Poll START_SHUTDOWN once a second so that we don't keep the runtime alive forever.
+		let requests = stream.map(Some).map_err(|_| ())
+			.select(Interval::new_interval(Duration::from_secs(1)).map(|_| None).map_err(|_| ()))
+			.take_while(|_| future::ok(!START_SHUTDOWN.load(Ordering::Relaxed)))
+			.filter_map(move |req| req.and_then(|(req, addr)| {
+				responder.respond(&req, MAX_UDP_RESPONSE).map(|resp| (Bytes::from(resp), addr))
+			}));
+		tokio::spawn(requests.forward(sink.sink_map_err(|_| ())).then(move |_| {
+			if START_SHUTDOWN.load(Ordering::Relaxed) {
+				return future::Either::A(future::ok(()));
+			}
+			printer.add_line("DNS UDP listener failed, restarting".to_string(), true);
+			future::Either::B(Delay::new(Instant::now() + Duration::from_secs(1)).then(move |_| {
+				DnsServer::listen_udp(server, bind, printer);
+				future::ok(())
+			}))
+		}));
+	}
+
+	fn listen_tcp(server: Arc<DnsServer>, bind: SocketAddr, printer: &'static Printer) {
+		let listener = match TcpListener::bind(&bind) {
+			Ok(listener) => listener,
+			Err(e) => {
+				printer.add_line(format!("Failed to bind DNS TCP socket to {}: {:?}", bind, e), true);
+				return;
+			},
+		};
+		let responder = Arc::clone(&server);
+		let conns = listener.incoming().map(Some).map_err(|_| ())
+			.select(Interval::new_interval(Duration::from_secs(1)).map(|_| None).map_err(|_| ()))
+			.take_while(|_| future::ok(!START_SHUTDOWN.load(Ordering::Relaxed)));
+		tokio::spawn(conns.for_each(move |conn| {
+			if let Some(stream) = conn {
+				let conn_responder = Arc::clone(&responder);
+				let codec = length_delimited::Builder::new().length_field_length(2).new_codec();
+				let (sink, stream) = Framed::new(stream, codec).split();
+				tokio::spawn(TimeoutStream::new_persistent(stream, Duration::from_secs(10)).map_err(|_| ())
+					.filter_map(move |req| conn_responder.respond(&req, MAX_TCP_RESPONSE).map(Bytes::from))
+					.forward(sink.sink_map_err(|_| ()))
+					.then(|_| future::ok(())));
+			}
+			future::ok(())
+		}).then(move |_| {
+			if START_SHUTDOWN.load(Ordering::Relaxed) {
+				return future::Either::A(future::ok(()));
+			}
+			printer.add_line("DNS TCP listener failed, restarting".to_string(), true);
+			future::Either::B(Delay::new(Instant::now() + Duration::from_secs(1)).then(move |_| {
+				DnsServer::listen_tcp(server, bind, printer);
+				future::ok(())
+			}))
+		}));
+	}
+
+	/// Starts answering A/AAAA queries for the zone's origin, ie the seed name (and its x<hex>
+	/// subdomains, for any allowed combination of service bits, plus the same under
+	/// cjdns.seed_name for CJDNS nodes) on bind, over both UDP and TCP. SOA and NS queries for the
+	/// seed name itself are answered from the zone.
+	pub fn start(bind: SocketAddr, zone: ZoneConfig, store: &'static Store, bgp_client: Arc<BGPClient>, printer: &'static Printer) {
+		let seed_name = zone.origin().to_string();
+		let server = Arc::new(DnsServer {
+			seed_name: seed_name.trim_end_matches('.').split('.').map(|l| l.to_string()).collect(),
+			zone,
+			store,
+			bgp_client,
+		});
+		DnsServer::listen_udp(Arc::clone(&server), bind, printer);
+		DnsServer::listen_tcp(server, bind, printer);
+		printer.add_line(format!("Serving DNS for {} on {}", seed_name, bind), false);
+	}
+}
+
+#[test]
+fn test_parse_question() {
+	// ID 0x1234, RD, one question for x9.Seed.Example.com IN AAAA
+	let mut req = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+	for label in ["x9", "Seed", "Example", "com"].iter() {
+		req.push(label.len() as u8);
+		req.extend_from_slice(label.as_bytes());
+	}
+	req.extend_from_slice(&[0, 0, 28, 0, 1]);
+	let question = parse_question(&req).unwrap();
+	assert_eq!(question.labels, vec!["x9", "seed", "example", "com"]);
+	assert_eq!(question.qtype, TYPE_AAAA);
+	assert_eq!(question.qclass, CLASS_IN);
+	assert_eq!(question.len, req.len());
+
+	let seed_name = vec!["seed".to_string(), "example".to_string(), "com".to_string()];
+	assert_eq!(services_for_name(&seed_name, &question.labels, 0xc4d), Ok(SeedName::Nodes { services: 9, cjdns: false }));
+	assert_eq!(services_for_name(&seed_name, &question.labels[1..], 0xc4d), Ok(SeedName::Nodes { services: DEFAULT_DNS_SERVICES, cjdns: false }));
+	assert_eq!(services_for_name(&seed_name, &question.labels[2..], 0xc4d), Err(RCODE_REFUSED));
+	let name = |l: &str| vec![l.to_string(), "seed".to_string(), "example".to_string(), "com".to_string()];
+	assert_eq!(services_for_name(&seed_name, &name("x409"), 0xc4d), Ok(SeedName::Nodes { services: 0x409, cjdns: false }));
+	assert_eq!(services_for_name(&seed_name, &name("x849"), 0xc4d), Ok(SeedName::Nodes { services: 0x849, cjdns: false }));
+	assert_eq!(services_for_name(&seed_name, &name("cjdns"), 0xc4d), Ok(SeedName::Nodes { services: DEFAULT_DNS_SERVICES, cjdns: true }));
+	let mut cjdns_name = name("cjdns");
+	cjdns_name.insert(0, "x9".to_string());
+	assert_eq!(services_for_name(&seed_name, &cjdns_name, 0xc4d), Ok(SeedName::Nodes { services: 9, cjdns: true }));
+	cjdns_name.swap(0, 1);
+	assert_eq!(services_for_name(&seed_name, &cjdns_name, 0xc4d), Err(RCODE_NXDOMAIN));
+	for label in ["x0", "x3", "x09", "y9", "x9x", "x"].iter() {
+		assert_eq!(services_for_name(&seed_name, &name(label), 0xc4d), Err(RCODE_NXDOMAIN));
+	}
+
+	let srv_name = |ls: &[&str]| ls.iter().map(|l| l.to_string()).chain(seed_name.iter().cloned()).collect::<Vec<_>>();
+	assert_eq!(services_for_name(&seed_name, &srv_name(&["_bitcoin", "_tcp"]), 0xc4d), Ok(SeedName::Srv { services: DEFAULT_DNS_SERVICES }));
+	assert_eq!(services_for_name(&seed_name, &srv_name(&["_bitcoin", "_tcp", "x9"]), 0xc4d), Ok(SeedName::Srv { services: 9 }));
+	assert_eq!(services_for_name(&seed_name, &srv_name(&["_bitcoin", "_tcp", "cjdns"]), 0xc4d), Err(RCODE_NXDOMAIN));
+	assert_eq!(services_for_name(&seed_name, &srv_name(&["x9", "_bitcoin", "_tcp"]), 0xc4d), Err(RCODE_NXDOMAIN));
+	assert_eq!(services_for_name(&seed_name, &srv_name(&["n01020304208e"]), 0xc4d), Ok(SeedName::SrvTarget("1.2.3.4:8334".parse().unwrap())));
+	assert_eq!(services_for_name(&seed_name, &srv_name(&["n01020304208e", "x9"]), 0xc4d), Err(RCODE_NXDOMAIN));
+
+	// Compression pointers and truncated questions are rejected
+	req[12] = 0xc0;
+	assert_eq!(parse_question(&req).err(), Some(RCODE_FORMERR));
+	assert_eq!(parse_question(&req[..req.len() - 1]).err(), Some(RCODE_FORMERR));
+}
@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+
+use ed25519_dalek::{Signer, SigningKey};
+
+use rand::RngCore;
+
+use crate::zone::{name_to_wire, type_to_str, Record, TYPE_DNSKEY, TYPE_NSEC, TYPE_RRSIG};
+
+const ALGORITHM_ED25519: u8 = 15;
+const FLAGS_ZSK: u16 = 256;
+const FLAGS_KSK: u16 = 257;
+/// Signatures are valid from an hour before they're made (in case resolvers' clocks are behind)
+/// for a week. We re-sign every time we write the zone, so this only matters if we stop writing.
+const SIG_INCEPTION_OFFSET: u32 = 3600;
+const SIG_VALIDITY: u32 = 7 * 86400;
+
+fn encode_base64(inp: &[u8]) -> String {
+	let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+	let mut ret = String::with_capacity(inp.len().div_ceil(3) * 4);
+	for chunk in inp.chunks(3) {
+		let acc = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+		for i in 0..4 {
+			if i <= chunk.len() {
+				ret.push(alphabet[(acc >> (18 - 6 * i)) as usize & 63] as char);
+			} else {
+				ret.push('=');
+			}
+		}
+	}
+	ret
+}
+
+/// Gets a sort key which orders (lowercase, fully-qualified) names in DNSSEC canonical order (RFC
+/// 4034 6.1), ie label-by-label from the root.
+fn canonical_key(name: &str) -> Vec<Vec<u8>> {
+	name.trim_end_matches('.').split('.').filter(|l| !l.is_empty()).rev().map(|l| l.as_bytes().to_vec()).collect()
+}
+
+/// Encodes an NSEC type bitmap (RFC 4034 4.1.2). types must be sorted.
+fn type_bitmap(types: &[u16]) -> Vec<u8> {
+	let mut res = Vec::new();
+	let mut window_start = 0;
+	while window_start < types.len() {
+		let window = types[window_start] >> 8;
+		let window_types: Vec<u16> = types[window_start..].iter().take_while(|t| *t >> 8 == window).cloned().collect();
+		let mut bitmap = vec![0u8; (*window_types.last().unwrap() as usize & 0xff) / 8 + 1];
+		for t in window_types.iter() {
+			bitmap[(*t as usize & 0xff) / 8] |= 0x80 >> (t & 7);
+		}
+		res.push(window as u8);
+		res.push(bitmap.len() as u8);
+		res.extend_from_slice(&bitmap);
+		window_start += window_types.len();
+	}
+	res
+}
+
+/// A name's records, by type
+type RRsets = BTreeMap<u16, Vec<Record>>;
+
+struct Key {
+	signing_key: SigningKey,
+	flags: u16,
+}
+
+impl Key {
+	fn load_or_generate(path: &str, flags: u16) -> std::io::Result<Key> {
+		let secret = match fs::read_to_string(path) {
+			Ok(hex) => {
+				let bytes = Vec::<u8>::from_hex(hex.trim())
+					.map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid DNSSEC key hex"))?;
+				if bytes.len() != 32 {
+					return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "DNSSEC keys must be 32 bytes"));
+				}
+				let mut secret = [0; 32];
+				secret.copy_from_slice(&bytes);
+				secret
+			},
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+				let mut secret = [0; 32];
+				rand::thread_rng().fill_bytes(&mut secret);
+				// Only we should be able to read the key, and create_new stops another instance
+				// generating a different key at the same time from overwriting ours
+				let mut options = fs::OpenOptions::new();
+				options.write(true).create_new(true);
+				#[cfg(unix)]
+				options.mode(0o600);
+				let mut f = options.open(path)?;
+				f.write_all((secret.to_hex() + "\n").as_bytes())?;
+				f.sync_all()?;
+				secret
+			},
+			Err(e) => return Err(e),
+		};
+		Ok(Key { signing_key: SigningKey::from_bytes(&secret), flags })
+	}
+
+	fn dnskey_rdata(&self) -> Vec<u8> {
+		let mut res = Vec::with_capacity(4 + 32);
+		res.extend_from_slice(&self.flags.to_be_bytes());
+		res.push(3); // Protocol, which is always 3
+		res.push(ALGORITHM_ED25519);
+		res.extend_from_slice(self.signing_key.verifying_key().as_bytes());
+		res
+	}
+
+	fn dnskey(&self, origin: &str, ttl: u32) -> Record {
+		Record {
+			name: origin.to_string(),
+			rtype: TYPE_DNSKEY,
+			ttl,
+			rdata: self.dnskey_rdata(),
+			text: format!("{} 3 {} {}", self.flags, ALGORITHM_ED25519, encode_base64(self.signing_key.verifying_key().as_bytes())),
+		}
+	}
+
+	/// The key tag, per RFC 4034 appendix B
+	fn key_tag(&self) -> u16 {
+		let mut acc: u32 = 0;
+		for (i, b) in self.dnskey_rdata().iter().enumerate() {
+			acc += if i & 1 == 0 { (*b as u32) << 8 } else { *b as u32 };
+		}
+		acc += (acc >> 16) & 0xffff;
+		(acc & 0xffff) as u16
+	}
+
+	/// Signs an RRset (which must be non-empty, with all records sharing a name, type and TTL).
+	fn sign(&self, origin: &str, rrset: &[Record], inception: u32, expiration: u32) -> Record {
+		let name = &rrset[0].name;
+		let labels = name.trim_end_matches('.').split('.').filter(|l| !l.is_empty()).count() as u8;
+		let mut rdata = Vec::new();
+		rdata.extend_from_slice(&rrset[0].rtype.to_be_bytes());
+		rdata.push(ALGORITHM_ED25519);
+		rdata.push(labels);
+		rdata.extend_from_slice(&rrset[0].ttl.to_be_bytes());
+		rdata.extend_from_slice(&expiration.to_be_bytes());
+		rdata.extend_from_slice(&inception.to_be_bytes());
+		rdata.extend_from_slice(&self.key_tag().to_be_bytes());
+		rdata.extend_from_slice(&name_to_wire(origin));
+
+		let mut rdatas: Vec<&Vec<u8>> = rrset.iter().map(|r| &r.rdata).collect();
+		rdatas.sort();
+		rdatas.dedup();
+		let mut signed_data = rdata.clone();
+		let name_wire = name_to_wire(name);
+		for r in rdatas {
+			signed_data.extend_from_slice(&name_wire);
+			signed_data.extend_from_slice(&rrset[0].rtype.to_be_bytes());
+			signed_data.extend_from_slice(&1u16.to_be_bytes()); // Class IN
+			signed_data.extend_from_slice(&rrset[0].ttl.to_be_bytes());
+			signed_data.extend_from_slice(&(r.len() as u16).to_be_bytes());
+			signed_data.extend_from_slice(r);
+		}
+		let signature = self.signing_key.sign(&signed_data).to_bytes();
+		rdata.extend_from_slice(&signature);
+
+		Record {
+			name: name.clone(),
+			rtype: TYPE_RRSIG,
+			ttl: rrset[0].ttl,
+			rdata,
+			text: format!("{} {} {} {} {} {} {} {} {}", type_to_str(rrset[0].rtype), ALGORITHM_ED25519, labels,
+				rrset[0].ttl, expiration, inception, self.key_tag(), origin, encode_base64(&signature)),
+		}
+	}
+}
+
+/// A key-signing key, which only signs the DNSKEY RRset (and whose DS record goes in the parent
+/// zone), and a zone-signing key which signs everything else.
+pub struct DnssecKeys {
+	ksk: Key,
+	zsk: Key,
+}
+
+impl DnssecKeys {
+	/// Loads the keys from dnssec_ksk and dnssec_zsk (as hex-encoded Ed25519 secret keys) in the
+	/// given store, generating and saving any which don't exist yet.
+	pub fn load_or_generate(store: &str) -> std::io::Result<DnssecKeys> {
+		Ok(DnssecKeys {
+			ksk: Key::load_or_generate(&(store.to_string() + "/dnssec_ksk"), FLAGS_KSK)?,
+			zsk: Key::load_or_generate(&(store.to_string() + "/dnssec_zsk"), FLAGS_ZSK)?,
+		})
+	}
+
+	/// Gets the (SHA-256) DS record for our KSK, to be published in the parent zone.
+	pub fn ds_record(&self, origin: &str) -> String {
+		let mut data = name_to_wire(origin);
+		data.extend_from_slice(&self.ksk.dnskey_rdata());
+		format!("{}\tIN\tDS\t{} {} 2 {}", origin, self.ksk.key_tag(), ALGORITHM_ED25519, sha256::Hash::hash(&data)[..].to_hex())
+	}
+
+	/// Adds our DNSKEYs, an NSEC chain and RRSIGs over every RRset to the given zone. The result is
+	/// in canonical order.
+	pub fn sign_zone(&self, origin: &str, records: Vec<Record>, ttl: u32, nsec_ttl: u32, now: u32) -> Vec<Record> {
+		let inception = now - SIG_INCEPTION_OFFSET;
+		let expiration = now + SIG_VALIDITY;
+
+		// Group the records into RRsets by name (in canonical order) and then type.
+		let mut names: BTreeMap<Vec<Vec<u8>>, (String, RRsets)> = BTreeMap::new();
+		let mut add_record = |record: Record| {
+			names.entry(canonical_key(&record.name)).or_insert_with(|| (record.name.clone(), BTreeMap::new())).1
+				.entry(record.rtype).or_default().push(record);
+		};
+		for record in records {
+			add_record(record);
+		}
+		add_record(self.ksk.dnskey(origin, ttl));
+		add_record(self.zsk.dnskey(origin, ttl));
+
+		let name_list: Vec<(String, Vec<u16>)> = names.values()
+			.map(|(name, rrsets)| (name.clone(), rrsets.keys().cloned().collect())).collect();
+		let mut res = Vec::new();
+		for (idx, (name, rrsets)) in names.into_values().enumerate() {
+			let next_name = &name_list[(idx + 1) % name_list.len()].0;
+			let mut types = name_list[idx].1.clone();
+			types.push(TYPE_RRSIG);
+			types.push(TYPE_NSEC);
+			types.sort_unstable();
+			let mut nsec_rdata = name_to_wire(next_name);
+			nsec_rdata.extend_from_slice(&type_bitmap(&types));
+			let nsec = Record {
+				name: name.clone(),
+				rtype: TYPE_NSEC,
+				ttl: nsec_ttl,
+				rdata: nsec_rdata,
+				text: format!("{} {}", next_name, types.iter().map(|t| type_to_str(*t)).collect::<Vec<_>>().join(" ")),
+			};
+
+			for (rtype, mut rrset) in rrsets.into_iter().chain(std::iter::once((TYPE_NSEC, vec![nsec]))) {
+				rrset.sort_by(|a, b| a.rdata.cmp(&b.rdata));
+				rrset.dedup_by(|a, b| a.rdata == b.rdata);
+				let key = if rtype == TYPE_DNSKEY { &self.ksk } else { &self.zsk };
+				let rrsig = key.sign(origin, &rrset, inception, expiration);
+				res.append(&mut rrset);
+				res.push(rrsig);
+			}
+		}
+		res
+	}
+}
+
+#[test]
+fn test_dnssec() {
+	// The Ed25519 example from RFC 8080
+	let key = Key { signing_key: SigningKey::from_bytes(b"82260384628080122645190204142262"), flags: FLAGS_KSK };
+	assert_eq!(key.dnskey("example.com.", 3600).text, "257 3 15 l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4=");
+	assert_eq!(key.key_tag(), 3613);
+	let keys = DnssecKeys { ksk: key, zsk: Key { signing_key: SigningKey::from_bytes(&[1; 32]), flags: FLAGS_ZSK } };
+	assert_eq!(keys.ds_record("example.com."),
+		"example.com.\tIN\tDS\t3613 15 2 3aa5ab37efce57f737fc1627013fee07bdf241bd10f3b1964ab55c78e79a304b");
+	let mut mx_rdata = vec![0, 10];
+	mx_rdata.extend_from_slice(&name_to_wire("mail.example.com."));
+	let mx = Record { name: "example.com.".to_string(), rtype: 15, ttl: 3600, rdata: mx_rdata, text: String::new() };
+	let rrsig = keys.ksk.sign("example.com.", &[mx], 1438207200, 1440021600);
+	assert_eq!(rrsig.text, "TYPE15 15 2 3600 1440021600 1438207200 3613 example.com. oL9krJun7xfBOIWcGHi7mag5/hdZrKWw15jPGrHpjQeRAvTdszaPD+QLs3fx8A4M3e23mRZ9VrbpMngwcrqNAg==");
+
+	assert_eq!(encode_base64(b"f"), "Zg==");
+	assert_eq!(encode_base64(b"fo"), "Zm8=");
+	assert_eq!(encode_base64(b"foo"), "Zm9v");
+	assert!(canonical_key("example.com.") < canonical_key("a.example.com."));
+	assert!(canonical_key("z.example.com.") > canonical_key("a.b.example.com."));
+	// A, NS, SOA, AAAA, RRSIG and NSEC (RFC 4034 4.3)
+	assert_eq!(type_bitmap(&[1, 2, 6, 28, 46, 47]), vec![0, 6, 0x62, 0, 0, 0x08, 0, 0x03]);
+
+	// Every RRset in a signed zone is followed by its RRSIG and there's an NSEC chain back to the apex
+	let a = |name: &str, octet: u8| Record { name: name.to_string(), rtype: crate::zone::TYPE_A, ttl: 60, rdata: vec![1, 2, 3, octet], text: String::new() };
+	let signed = keys.sign_zone("example.com.", vec![a("x9.example.com.", 1), a("example.com.", 1), a("example.com.", 2), a("example.com.", 1)], 60, 30, 1700000000);
+	let summary: Vec<(&str, u16)> = signed.iter().map(|r| (r.name.as_str(), r.rtype)).collect();
+	assert_eq!(summary, vec![
+		("example.com.", crate::zone::TYPE_A), ("example.com.", crate::zone::TYPE_A), ("example.com.", TYPE_RRSIG),
+		("example.com.", TYPE_DNSKEY), ("example.com.", TYPE_DNSKEY), ("example.com.", TYPE_RRSIG),
+		("example.com.", TYPE_NSEC), ("example.com.", TYPE_RRSIG),
+		("x9.example.com.", crate::zone::TYPE_A), ("x9.example.com.", TYPE_RRSIG), ("x9.example.com.", TYPE_NSEC), ("x9.example.com.", TYPE_RRSIG),
+	]);
+	assert_eq!(signed[6].text, "x9.example.com. A RRSIG NSEC DNSKEY");
+	assert_eq!(signed[10].text, "example.com. A RRSIG NSEC");
+	assert_eq!(signed[10].ttl, 30);
+	assert!(signed[5].text.starts_with("DNSKEY 15 2 60 1700604800 1699996400 3613 example.com. "));
+}
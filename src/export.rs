@@ -0,0 +1,164 @@
+use std::fmt::Write;
+use std::fs;
+use std::io;
+
+use crate::bgp_client::BGPClient;
+use crate::datastore::{AddressState, NodeInfo, SockAddr, Store};
+
+/// Parses an address from an import file. We take anything the console does (IP:port, onion:port
+/// or .b32.i2p:port), as well as addresses without a port, which get the network's default port.
+pub fn parse_addr_line(line: &str, default_port: u16) -> Option<SockAddr> {
+	if let Ok(addr) = line.parse() { return Some(addr); }
+	if line.contains(':') && !line.starts_with('[') {
+		// A bare IPv6 address
+		format!("[{}]:{}", line, default_port).parse().ok()
+	} else {
+		format!("{}:{}", line, default_port).parse().ok()
+	}
+}
+
+/// Adds every address in the given file (one per line, with # comments) to the store as a fresh
+/// address. Returns the number of addresses read, how many were new to us, and the line numbers
+/// of any lines we couldn't parse.
+pub fn import_addrs(store: &Store, path: &str) -> Result<(usize, u64, Vec<usize>), io::Error> {
+	let contents = fs::read_to_string(path)?;
+	let mut addrs = Vec::new();
+	let mut bad_lines = Vec::new();
+	for (idx, line) in contents.lines().enumerate() {
+		let line = line.split('#').next().unwrap().trim();
+		if line.is_empty() { continue; }
+		match parse_addr_line(line, store.get_network().default_port) {
+			Some(addr) => addrs.push(addr),
+			None => bad_lines.push(idx + 1),
+		}
+	}
+	let count = addrs.len();
+	Ok((count, store.add_fresh_addrs(addrs.into_iter()), bad_lines))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+	Csv,
+	Json,
+}
+
+/// Which nodes to export. Nodes must match everything which is set.
+#[derive(Default)]
+pub struct ExportFilter {
+	pub state: Option<AddressState>,
+	/// Nodes must have all of these services
+	pub services: u64,
+	pub asn: Option<u32>,
+}
+
+fn csv_field(s: &str) -> String {
+	if s.contains([',', '"', '\n']) {
+		format!("\"{}\"", s.replace('"', "\"\""))
+	} else {
+		s.to_string()
+	}
+}
+
+fn json_string(s: &str) -> String {
+	let mut res = String::with_capacity(s.len() + 2);
+	res.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => res.push_str("\\\""),
+			'\\' => res.push_str("\\\\"),
+			c if c < ' ' => write!(res, "\\u{:04x}", c as u32).unwrap(),
+			c => res.push(c),
+		}
+	}
+	res.push('"');
+	res
+}
+
+const CSV_HEADER: &str = "address,network,state,services,last_good,last_seen,last_attempt,asn,user_agent,protocol_version,start_height,rtt_ms,history\n";
+
+/// Renders (address, record, ASN) rows. Timestamps are UNIX seconds (0 for never) and the history
+/// is the node's recent scan outcomes, most recent first.
+fn render(rows: &[(SockAddr, NodeInfo, u32)], format: ExportFormat) -> String {
+	let mut res = String::new();
+	if format == ExportFormat::Csv {
+		res.push_str(CSV_HEADER);
+	} else {
+		res.push_str("[\n");
+	}
+	for (idx, (addr, info, asn)) in rows.iter().enumerate() {
+		let version = info.details.as_ref().and_then(|details| details.version.as_ref());
+		let rtt = info.details.as_ref().and_then(|details| details.rtt_ms);
+		let history: Vec<&str> = info.history.iter().map(|state| state.to_str()).collect();
+		match format {
+			ExportFormat::Csv => {
+				let opt = |v: Option<String>| v.unwrap_or_default();
+				writeln!(res, "{},{},{},{},{},{},{},{},{},{},{},{},{}", csv_field(&addr.to_string()), addr.network().to_str(),
+					info.state.to_str(), info.services, info.last_good, info.last_seen, info.last_attempt, asn,
+					csv_field(&opt(version.map(|v| v.user_agent.clone()))), opt(version.map(|v| v.protocol_version.to_string())),
+					opt(version.map(|v| v.start_height.to_string())), opt(rtt.map(|rtt| rtt.to_string())), history.join(";")).unwrap();
+			},
+			ExportFormat::Json => {
+				let opt = |v: Option<String>| v.unwrap_or_else(|| "null".to_string());
+				let history: Vec<String> = history.iter().map(|state| json_string(state)).collect();
+				write!(res, "{{\"address\":{},\"network\":{},\"state\":{},\"services\":{},\"last_good\":{},\"last_seen\":{},\"last_attempt\":{},\"asn\":{},\"user_agent\":{},\"protocol_version\":{},\"start_height\":{},\"rtt_ms\":{},\"history\":[{}]}}",
+					json_string(&addr.to_string()), json_string(addr.network().to_str()), json_string(info.state.to_str()),
+					info.services, info.last_good, info.last_seen, info.last_attempt, asn,
+					opt(version.map(|v| json_string(&v.user_agent))), opt(version.map(|v| v.protocol_version.to_string())),
+					opt(version.map(|v| v.start_height.to_string())), opt(rtt.map(|rtt| rtt.to_string())), history.join(",")).unwrap();
+				res.push_str(if idx == rows.len() - 1 { "\n" } else { ",\n" });
+			},
+		}
+	}
+	if format == ExportFormat::Json {
+		res.push_str("]\n");
+	}
+	res
+}
+
+/// Writes the nodes which match the filter to the given file, returning how many there were.
+/// Nodes which aren't on IPv4 or IPv6 have an ASN of 0.
+pub fn export_nodes(store: &Store, bgp_client: &BGPClient, path: &str, filter: &ExportFilter, format: ExportFormat) -> Result<usize, io::Error> {
+	let mut rows: Vec<_> = store.get_nodes(filter.state, filter.services).into_iter().filter_map(|(addr, info)| {
+		let asn = addr.ip().map(|ip| bgp_client.get_asn(ip)).unwrap_or(0);
+		if filter.asn.map(|filter_asn| filter_asn == asn).unwrap_or(true) {
+			Some((addr, info, asn))
+		} else { None }
+	}).collect();
+	rows.sort_unstable_by_key(|(addr, _, _)| addr.to_string());
+	fs::write(path, render(&rows, format))?;
+	Ok(rows.len())
+}
+
+#[test]
+fn test_export() {
+	use crate::datastore::{NodeDetails, VersionInfo};
+
+	assert!(parse_addr_line("1.2.3.4:8333", 8333).unwrap() == "1.2.3.4:8333".parse().unwrap());
+	assert!(parse_addr_line("1.2.3.4", 18333).unwrap() == "1.2.3.4:18333".parse().unwrap());
+	assert!(parse_addr_line("2001:db8::1", 8333).unwrap() == "[2001:db8::1]:8333".parse().unwrap());
+	assert!(parse_addr_line("[2001:db8::1]:8334", 8333).unwrap() == "[2001:db8::1]:8334".parse().unwrap());
+	assert!(parse_addr_line("ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p", 0).is_some());
+	assert!(parse_addr_line("not an address", 8333).is_none());
+
+	let rows = vec![
+		("1.2.3.4:8333".parse().unwrap(), NodeInfo {
+			state: AddressState::Good, services: 0x409, first_seen: 0, last_good: 10, last_seen: 10, last_attempt: 10, failures: 0, reliability: None,
+			history: vec![AddressState::Good, AddressState::Timeout],
+			details: Some(NodeDetails {
+				version: Some(VersionInfo { user_agent: "/Satoshi:27.0.0/\"x,y\"".to_string(), protocol_version: 70016, services: 0x409, start_height: 850000 }),
+				version_time: 10, rtt_ms: Some(42), last_message: String::new(),
+			}),
+		}, 64496),
+		("[2001:db8::1]:8333".parse().unwrap(), NodeInfo {
+			state: AddressState::Untested, services: 0, first_seen: 0, last_good: 0, last_seen: 0, last_attempt: 0, failures: 1, reliability: None,
+			history: vec![], details: None,
+		}, 0),
+	];
+	assert_eq!(render(&rows, ExportFormat::Csv), CSV_HEADER.to_string() +
+		"1.2.3.4:8333,IPv4,Good,1033,10,10,10,64496,\"/Satoshi:27.0.0/\"\"x,y\"\"\",70016,850000,42,Good;Timeout\n\
+		[2001:db8::1]:8333,IPv6,Untested,0,0,0,0,0,,,,,\n");
+	assert_eq!(render(&rows, ExportFormat::Json), "[\n\
+		{\"address\":\"1.2.3.4:8333\",\"network\":\"IPv4\",\"state\":\"Good\",\"services\":1033,\"last_good\":10,\"last_seen\":10,\"last_attempt\":10,\"asn\":64496,\"user_agent\":\"/Satoshi:27.0.0/\\\"x,y\\\"\",\"protocol_version\":70016,\"start_height\":850000,\"rtt_ms\":42,\"history\":[\"Good\",\"Timeout\"]},\n\
+		{\"address\":\"[2001:db8::1]:8333\",\"network\":\"IPv6\",\"state\":\"Untested\",\"services\":0,\"last_good\":0,\"last_seen\":0,\"last_attempt\":0,\"asn\":0,\"user_agent\":null,\"protocol_version\":null,\"start_height\":null,\"rtt_ms\":null,\"history\":[]}\n\
+		]\n");
+}
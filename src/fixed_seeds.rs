@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+
+use bitcoin::network::constants::ServiceFlags;
+
+use crate::bgp_client::BGPClient;
+use crate::datastore::{AddressState, NetworkType, SockAddr, Store};
+
+/// The most fixed seeds we'll write, as in Bitcoin Core's contrib/seeds/makeseeds.py
+const MAX_SEEDS: usize = 512;
+/// The reliability window (see datastore::RELIABILITY_WINDOWS) fixed seeds are picked on, ie 30 days
+const UPTIME_WINDOW: usize = 4;
+/// Nodes need at least this uptime over UPTIME_WINDOW to be a fixed seed, as well as to be reliable
+/// in it (which needs enough scans in the window that we aren't going on one or two)
+const MIN_UPTIME: f32 = 0.5;
+
+struct FixedSeed {
+	addr: SockAddr,
+	/// 0 for networks without ASNs
+	asn: u32,
+	uptime: f32,
+	user_agent: String,
+}
+
+/// Picks the nodes with the best uptime, with at most max_per_asn IPv4 and max_per_asn IPv6 nodes
+/// from any one ASN. IP nodes we have no route for are dropped, as we can't tell where they are.
+fn select_seeds(mut candidates: Vec<FixedSeed>, max_per_asn: usize) -> Vec<FixedSeed> {
+	candidates.sort_unstable_by(|a, b| b.uptime.total_cmp(&a.uptime)
+		.then_with(|| a.addr.to_string().cmp(&b.addr.to_string())));
+	let mut asn_counts = HashMap::new();
+	candidates.into_iter().filter(|seed| {
+		let network = seed.addr.network();
+		if network != NetworkType::IPv4 && network != NetworkType::IPv6 { return true; }
+		if seed.asn == 0 { return false; }
+		let count = asn_counts.entry((network.to_num(), seed.asn)).or_insert(0);
+		*count += 1;
+		*count <= max_per_asn
+	}).take(MAX_SEEDS).collect()
+}
+
+/// Gets the BIP155 network ID and address bytes for an address, if Bitcoin Core still supports
+/// its network.
+fn bip155_addr(addr: &SockAddr) -> Option<(u8, Vec<u8>)> {
+	match (addr.network(), addr.ip()) {
+		(NetworkType::IPv4, Some(IpAddr::V4(ip))) => Some((1, ip.octets().to_vec())),
+		(NetworkType::IPv6, Some(IpAddr::V6(ip))) => Some((2, ip.octets().to_vec())),
+		(NetworkType::Cjdns, Some(IpAddr::V6(ip))) => Some((6, ip.octets().to_vec())),
+		_ => match addr {
+			SockAddr::TorV3((pubkey, _)) => Some((4, pubkey.to_vec())),
+			SockAddr::I2P((hash, _)) => Some((5, hash.to_vec())),
+			_ => None,
+		},
+	}
+}
+
+/// Renders seeds as a nodes_main.txt, which contrib/seeds/generate-seeds.py reads. The details
+/// after each address are a comment, which it ignores.
+fn render_nodes(seeds: &[FixedSeed]) -> String {
+	let mut res = "# address # ASN, 30-day uptime, user agent\n".to_string();
+	for seed in seeds {
+		let asn = if seed.asn != 0 { format!("AS{}, ", seed.asn) } else { String::new() };
+		writeln!(res, "{} # {}{:.2}%, {}", seed.addr, asn, seed.uptime * 100.0, seed.user_agent).unwrap();
+	}
+	res
+}
+
+/// Renders seeds as Bitcoin Core's chainparamsseeds.h does, as BIP155-serialized (network ID,
+/// address, port) tuples.
+fn render_header(seeds: &[FixedSeed], network_name: &str) -> String {
+	let mut res = format!("#ifndef BITCOIN_CHAINPARAMSSEEDS_H\n#define BITCOIN_CHAINPARAMSSEEDS_H\n\
+		/**\n * List of fixed seed nodes for the bitcoin network\n * AUTOGENERATED by dnsseed-rust\n *\n\
+		 * Each line contains a BIP155 serialized (networkID, addr, port) tuple.\n */\n\
+		static const uint8_t chainparams_seed_{}[] = {{\n", network_name);
+	for seed in seeds {
+		let (network_id, addr_bytes) = match bip155_addr(&seed.addr) {
+			Some(addr) => addr,
+			None => continue,
+		};
+		let mut bytes = vec![network_id, addr_bytes.len() as u8];
+		bytes.extend_from_slice(&addr_bytes);
+		bytes.extend_from_slice(&seed.addr.port().to_be_bytes());
+		let bytes: Vec<String> = bytes.iter().map(|b| format!("0x{:02x}", b)).collect();
+		writeln!(res, "    {},", bytes.join(",")).unwrap();
+	}
+	res.push_str("};\n\n#endif // BITCOIN_CHAINPARAMSSEEDS_H\n");
+	res
+}
+
+/// Writes reliable Good full nodes on the default port (or I2P) with at least MIN_UPTIME over the last 30 days to
+/// nodes_path, in the nodes_main.txt format, and (if given) as a chainparamsseeds.h to
+/// header_path. Returns the number of seeds written.
+pub fn write_fixed_seeds(store: &Store, bgp_client: &BGPClient, nodes_path: &str, header_path: Option<&str>, max_per_asn: usize) -> Result<usize, io::Error> {
+	let network = store.get_network();
+	let candidates = store.get_nodes(Some(AddressState::Good), ServiceFlags::NETWORK.as_u64()).into_iter().filter_map(|(addr, info)| {
+		// I2P has no ports, so I2P nodes are always on port 0
+		if (addr.network() != NetworkType::I2P && addr.port() != network.default_port) || bip155_addr(&addr).is_none() { return None; }
+		let reliability = info.reliability?;
+		let uptime = reliability.uptime(UPTIME_WINDOW);
+		if uptime < MIN_UPTIME || !reliability.is_reliable_in(UPTIME_WINDOW) { return None; }
+		Some(FixedSeed {
+			asn: addr.ip().map(|ip| bgp_client.get_asn(ip)).unwrap_or(0),
+			user_agent: info.details?.version?.user_agent,
+			uptime, addr,
+		})
+	}).collect();
+	let seeds = select_seeds(candidates, max_per_asn);
+	fs::write(nodes_path, render_nodes(&seeds))?;
+	if let Some(header_path) = header_path {
+		fs::write(header_path, render_header(&seeds, network.core_seeds_name))?;
+	}
+	Ok(seeds.len())
+}
+
+#[test]
+fn test_fixed_seeds() {
+	let seed = |addr: &str, asn, uptime| FixedSeed { addr: addr.parse().unwrap(), asn, uptime, user_agent: "/Satoshi:27.0.0/".to_string() };
+	let seeds = select_seeds(vec![
+		seed("1.2.3.4:8333", 64496, 0.9),
+		seed("1.2.3.5:8333", 64496, 0.95),
+		seed("1.2.3.6:8333", 64496, 0.8),
+		seed("[2001:db8::1]:8333", 64496, 0.7),
+		seed("5.6.7.8:8333", 0, 1.0),
+		seed("ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p:0", 0, 0.6),
+	], 2);
+	// The worst node in the ASN is dropped, as is the one we have no ASN for, but IPv6 and I2P
+	// nodes have their own caps
+	let addrs: Vec<String> = seeds.iter().map(|seed| seed.addr.to_string()).collect();
+	assert_eq!(addrs, vec!["1.2.3.5:8333", "1.2.3.4:8333", "[2001:db8::1]:8333", "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p:0"]);
+
+	assert_eq!(render_nodes(&seeds[..1]), "# address # ASN, 30-day uptime, user agent\n1.2.3.5:8333 # AS64496, 95.00%, /Satoshi:27.0.0/\n");
+	let header = render_header(&seeds, "main");
+	assert!(header.contains("static const uint8_t chainparams_seed_main[] = {\n    0x01,0x04,0x01,0x02,0x03,0x05,0x20,0x8d,\n"));
+	assert!(header.contains("    0x02,0x10,0x20,0x01,0x0d,0xb8,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x01,0x20,0x8d,\n"));
+	assert!(header.contains("    0x05,0x20,0xa2,0x89,"));
+	assert!(header.ends_with("};\n\n#endif // BITCOIN_CHAINPARAMSSEEDS_H\n"));
+}
@@ -0,0 +1,180 @@
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use tokio::prelude::*;
+use tokio::prelude::future::{Either, Loop};
+use tokio::io::{read_exact, read_to_end, write_all};
+use tokio::net::TcpStream;
+use tokio::timer::{Delay, Interval};
+
+use rand::Rng;
+
+use crate::datastore::{NetworkType, Store};
+use crate::printer::Printer;
+
+use crate::START_SHUTDOWN;
+
+/// Reads a single newline-terminated SAM reply. We read byte-by-byte as a stream's payload may
+/// follow the reply immediately and we must not swallow it.
+fn read_line(stream: TcpStream) -> impl Future<Item=(TcpStream, String), Error=std::io::Error> {
+	future::loop_fn((stream, Vec::new()), |(stream, mut line)| {
+		read_exact(stream, [0u8; 1]).and_then(move |(stream, byte)| {
+			if byte[0] == b'\n' {
+				Ok(Loop::Break((stream, String::from_utf8_lossy(&line).into_owned())))
+			} else if line.len() > 4096 {
+				Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Oversized SAM reply"))
+			} else {
+				line.push(byte[0]);
+				Ok(Loop::Continue((stream, line)))
+			}
+		})
+	})
+}
+
+/// Sends a SAM command and checks that the reply is of the expected type and has RESULT=OK.
+fn sam_command(stream: TcpStream, command: String, reply: &'static str) -> impl Future<Item=(TcpStream, String), Error=std::io::Error> {
+	write_all(stream, command.into_bytes()).and_then(|(stream, _)| read_line(stream)).and_then(move |(stream, line)| {
+		if line.starts_with(reply) && line.split(' ').any(|arg| arg == "RESULT=OK") {
+			Ok((stream, line))
+		} else {
+			Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("SAM bridge error: {}", line)))
+		}
+	})
+}
+
+fn sam_hello(sam_bridge: &SocketAddr) -> impl Future<Item=TcpStream, Error=std::io::Error> {
+	TcpStream::connect(sam_bridge).and_then(|stream| {
+		sam_command(stream, "HELLO VERSION MIN=3.1 MAX=3.1\n".to_string(), "HELLO REPLY ")
+	}).map(|(stream, _)| stream)
+}
+
+/// Creates a transient streaming session with the given ID, returning the control socket. The
+/// session only lives as long as the control socket remains open.
+fn create_session(sam_bridge: &SocketAddr, session_id: String) -> impl Future<Item=TcpStream, Error=std::io::Error> {
+	sam_hello(sam_bridge).and_then(move |stream| {
+		sam_command(stream, format!("SESSION CREATE STYLE=STREAM ID={} DESTINATION=TRANSIENT SIGNATURE_TYPE=7 i2cp.leaseSetEncType=4,0\n", session_id),
+			"SESSION STATUS ")
+	}).map(|(stream, _)| stream)
+}
+
+/// Opens a stream to the given .b32.i2p hostname over an existing session.
+fn connect(sam_bridge: &SocketAddr, session_id: String, hostname: String) -> impl Future<Item=TcpStream, Error=std::io::Error> {
+	sam_hello(sam_bridge).and_then(move |stream| {
+		sam_command(stream, format!("NAMING LOOKUP NAME={}\n", hostname), "NAMING REPLY ")
+	}).and_then(move |(stream, reply)| {
+		match reply.split(' ').find_map(|arg| arg.strip_prefix("VALUE=")) {
+			Some(destination) => {
+				let connect_msg = format!("STREAM CONNECT ID={} DESTINATION={} SILENT=false\n", session_id, destination);
+				Either::A(sam_command(stream, connect_msg, "STREAM STATUS ").map(|(stream, _)| stream))
+			},
+			None => Either::B(future::err(std::io::Error::new(std::io::ErrorKind::InvalidData, "SAM naming reply missing value"))),
+		}
+	})
+}
+
+/// A SAM v3 session on a local I2P router, through which we open streams to I2P peers.
+pub struct I2PSession {
+	sam_bridge: SocketAddr,
+	session_id: Mutex<Option<String>>,
+}
+
+impl I2PSession {
+	/// Opens a stream to the given .b32.i2p hostname, failing if our session isn't up.
+	pub fn connect(&self, hostname: String) -> impl Future<Item=TcpStream, Error=std::io::Error> {
+		match self.session_id.lock().unwrap().clone() {
+			Some(session_id) => Either::A(connect(&self.sam_bridge, session_id, hostname)),
+			None => Either::B(future::err(std::io::Error::new(std::io::ErrorKind::NotConnected, "No I2P session"))),
+		}
+	}
+
+	fn run_session(&'static self, store: &'static Store, printer: &'static Printer) {
+		let session_id = format!("dnsseed-{:016x}", rand::thread_rng().gen::<u64>());
+		tokio::spawn(create_session(&self.sam_bridge, session_id.clone()).map_err(move |e| {
+			printer.add_line(format!("Failed to create I2P session: {:?}", e), true);
+		}).and_then(move |control_stream| {
+			*self.session_id.lock().unwrap() = Some(session_id);
+			store.set_network_reachable(NetworkType::I2P, true);
+			printer.add_line("Created I2P session".to_string(), false);
+
+			// Hold the control socket open until the router drops it or we shut down.
+			let shutdown = Interval::new_interval(Duration::from_secs(1)).map_err(|_| ())
+				.take_while(|_| future::ok(!START_SHUTDOWN.load(Ordering::Relaxed)))
+				.for_each(|_| future::ok(()));
+			read_to_end(control_stream, Vec::new()).map(|_| ()).map_err(|_| ())
+				.select(shutdown).then(|_| future::ok(()))
+		}).then(move |_: Result<(), ()>| {
+			*self.session_id.lock().unwrap() = None;
+			store.set_network_reachable(NetworkType::I2P, false);
+			if START_SHUTDOWN.load(Ordering::Relaxed) {
+				return Either::A(future::ok(()));
+			}
+			printer.add_line("No I2P session, retrying in 30 seconds".to_string(), true);
+			Either::B(Delay::new(Instant::now() + Duration::from_secs(30)).then(move |_| {
+				self.run_session(store, printer);
+				future::ok(())
+			}))
+		}));
+	}
+
+	/// Starts a (transient) session on the SAM bridge, recreating it any time it is lost. I2P
+	/// nodes are only marked reachable, and thus scanned, while the session is up.
+	pub fn start(sam_bridge: SocketAddr, store: &'static Store, printer: &'static Printer) -> &'static I2PSession {
+		let session: &'static I2PSession = Box::leak(Box::new(I2PSession {
+			sam_bridge,
+			session_id: Mutex::new(None),
+		}));
+		session.run_session(store, printer);
+		session
+	}
+}
+
+#[test]
+fn test_sam_stub() {
+	use std::io::{BufRead, BufReader, Write};
+
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let sam_bridge = listener.local_addr().unwrap();
+	let stub = std::thread::spawn(move || {
+		let mut lines = Vec::new();
+		let mut conns = Vec::new();
+		for reply in [&["HELLO REPLY RESULT=OK VERSION=3.1\n", "SESSION STATUS RESULT=OK DESTINATION=privkey\n"][..],
+		              &["HELLO REPLY RESULT=OK VERSION=3.1\n", "NAMING REPLY RESULT=OK NAME=x.b32.i2p VALUE=dest\n",
+		                "STREAM STATUS RESULT=OK\nversion"][..]].iter() {
+			let (mut conn, _) = listener.accept().unwrap();
+			let mut reader = BufReader::new(conn.try_clone().unwrap());
+			for line in reply.iter() {
+				let mut req = String::new();
+				reader.read_line(&mut req).unwrap();
+				lines.push(req);
+				conn.write_all(line.as_bytes()).unwrap();
+			}
+			conns.push(conn);
+		}
+		lines
+	});
+
+	let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+	let _control = rt.block_on(create_session(&sam_bridge, "id".to_string())).unwrap();
+	let stream = rt.block_on(connect(&sam_bridge, "id".to_string(), "x.b32.i2p".to_string())).unwrap();
+	let (_, payload) = rt.block_on(read_exact(stream, [0u8; 7])).unwrap();
+	assert_eq!(&payload, b"version");
+
+	assert_eq!(stub.join().unwrap(), vec![
+		"HELLO VERSION MIN=3.1 MAX=3.1\n".to_string(),
+		"SESSION CREATE STYLE=STREAM ID=id DESTINATION=TRANSIENT SIGNATURE_TYPE=7 i2cp.leaseSetEncType=4,0\n".to_string(),
+		"HELLO VERSION MIN=3.1 MAX=3.1\n".to_string(),
+		"NAMING LOOKUP NAME=x.b32.i2p\n".to_string(),
+		"STREAM CONNECT ID=id DESTINATION=dest SILENT=false\n".to_string(),
+	]);
+
+	// Failures are reported rather than treated as a connected stream
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let sam_bridge = listener.local_addr().unwrap();
+	std::thread::spawn(move || {
+		let (mut conn, _) = listener.accept().unwrap();
+		conn.write_all(b"HELLO REPLY RESULT=NOVERSION\n").unwrap();
+	});
+	assert!(rt.block_on(create_session(&sam_bridge, "id".to_string())).is_err());
+}
@@ -5,6 +5,13 @@ mod peer;
 mod bgp_client;
 mod timeout_stream;
 mod datastore;
+mod dns;
+mod i2p;
+mod network;
+mod zone;
+mod dnssec;
+mod export;
+mod fixed_seeds;
 
 use std::env;
 use std::collections::HashMap;
@@ -14,19 +21,24 @@ use std::time::{Duration, Instant};
 use std::net::{SocketAddr, ToSocketAddrs};
 
 use bitcoin::blockdata::block::Block;
-use bitcoin::blockdata::constants::genesis_block;
 use bitcoin::hash_types::{BlockHash};
-use bitcoin::network::constants::{Network, ServiceFlags};
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::network::constants::ServiceFlags;
 use bitcoin::network::message::NetworkMessage;
 use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
 //use bitcoin::util::hash::BitcoinHash;
 
 use printer::{Printer, Stat};
 use peer::Peer;
-use datastore::{AddressState, Store, U64Setting, RegexSetting};
+use datastore::{AddressState, NetworkType, Store, U64Setting, RegexSetting, SockAddr, VersionInfo};
 use timeout_stream::TimeoutStream;
 use rand::Rng;
 use bgp_client::BGPClient;
+use dns::DnsServer;
+use i2p::I2PSession;
+use network::NetworkParams;
+use zone::ZoneConfig;
+use dnssec::DnssecKeys;
 
 use tokio::prelude::*;
 use tokio::timer::Delay;
@@ -38,6 +50,8 @@ static mut HEIGHT_MAP: Option<Box<Mutex<HashMap<u64, BlockHash>>>> = None;
 static mut DATA_STORE: Option<Box<Store>> = None;
 static mut PRINTER: Option<Box<Printer>> = None;
 static mut TOR_PROXY: Option<SocketAddr> = None;
+static mut I2P_SESSION: Option<&'static I2PSession> = None;
+static mut NETWORK: Option<Box<NetworkParams>> = None;
 pub static START_SHUTDOWN: AtomicBool = AtomicBool::new(false);
 static SCANNING: AtomicBool = AtomicBool::new(false);
 
@@ -86,6 +100,9 @@ static ALLOC: MemoryLimitingAllocator = MemoryLimitingAllocator;
 struct PeerState {
 	request: Arc<(u64, BlockHash, Block)>,
 	pong_nonce: u64,
+	ping_sent: Option<Instant>,
+	rtt: Option<Duration>,
+	version: Option<VersionInfo>,
 	node_services: u64,
 	msg: (String, bool),
 	fail_reason: AddressState,
@@ -96,10 +113,14 @@ struct PeerState {
 	recvd_block: bool,
 }
 
-pub fn scan_node(scan_time: Instant, node: SocketAddr, manual: bool) {
-	if START_SHUTDOWN.load(Ordering::Relaxed) { return; }
+/// Scans the given node at scan_time. Returns false (without scanning it) if we're shutting down
+/// or a connection cap has been reached, though manual scans ignore the caps. The connection is
+/// reserved until the scan completes, including while we wait for scan_time.
+pub fn scan_node(scan_time: Instant, node: SockAddr, manual: bool) -> bool {
+	if START_SHUTDOWN.load(Ordering::Relaxed) { return false; }
 	let printer = unsafe { PRINTER.as_ref().unwrap() };
 	let store = unsafe { DATA_STORE.as_ref().unwrap() };
+	if !store.try_open_connection(&node, manual) { return false; }
 
 	let mut rng = rand::thread_rng();
 	let peer_state = Arc::new(Mutex::new(PeerState {
@@ -109,6 +130,9 @@ pub fn scan_node(scan_time: Instant, node: SocketAddr, manual: bool) {
 		recvd_addrs: false,
 		recvd_block: false,
 		pong_nonce: rng.gen(),
+		ping_sent: None,
+		rtt: None,
+		version: None,
 		node_services: 0,
 		fail_reason: AddressState::Timeout,
 		msg: (String::new(), false),
@@ -117,10 +141,11 @@ pub fn scan_node(scan_time: Instant, node: SocketAddr, manual: bool) {
 	let err_peer_state = Arc::clone(&peer_state);
 	let final_peer_state = Arc::clone(&peer_state);
 
+	let peer_node = node.clone();
 	let peer = Delay::new(scan_time).then(move |_| {
 		printer.set_stat(Stat::NewConnection);
 		let timeout = store.get_u64(U64Setting::RunTimeout);
-		Peer::new(node.clone(), unsafe { TOR_PROXY.as_ref().unwrap() }, Duration::from_secs(timeout), printer)
+		Peer::new(peer_node, unsafe { NETWORK.as_ref().unwrap() }.magic, unsafe { TOR_PROXY.as_ref().unwrap() }, unsafe { I2P_SESSION }, Duration::from_secs(timeout), printer)
 	});
 	tokio::spawn(peer.and_then(move |(mut write, read)| {
 		TimeoutStream::new_timeout(read, scan_time + Duration::from_secs(store.get_u64(U64Setting::RunTimeout)))
@@ -140,11 +165,19 @@ pub fn scan_node(scan_time: Instant, node: SocketAddr, manual: bool) {
 			state_lock.fail_reason = AddressState::TimeoutDuringRequest;
 			match msg {
 				Some(NetworkMessage::Version(ver)) => {
-					if ver.start_height < 0 || ver.start_height as u64 > state_lock.request.0 + 1008*2 {
+					let safe_ua = ver.user_agent.replace(|c: char| !c.is_ascii() || c < ' ' || c > '~', "");
+					state_lock.version = Some(VersionInfo {
+						user_agent: safe_ua.clone(),
+						protocol_version: ver.version,
+						services: ver.services.as_u64(),
+						start_height: ver.start_height,
+					});
+					let max_height = state_lock.request.0.saturating_add(store.get_u64(U64Setting::MaxHeightAhead));
+					if ver.start_height < 0 || ver.start_height as u64 > max_height {
+						state_lock.msg = (format!("({} > {})", ver.start_height, max_height), false);
 						state_lock.fail_reason = AddressState::HighBlockCount;
 						return future::err(());
 					}
-					let safe_ua = ver.user_agent.replace(|c: char| !c.is_ascii() || c < ' ' || c > '~', "");
 					if (ver.start_height as u64) < state_lock.request.0 {
 						state_lock.msg = (format!("({} < {})", ver.start_height, state_lock.request.0), true);
 						state_lock.fail_reason = AddressState::LowBlockCount;
@@ -181,6 +214,7 @@ pub fn scan_node(scan_time: Instant, node: SocketAddr, manual: bool) {
 					if let Err(_) = write.try_send(NetworkMessage::Ping(state_lock.pong_nonce)) {
 						return future::err(());
 					}
+					state_lock.ping_sent = Some(Instant::now());
 				},
 				Some(NetworkMessage::Ping(v)) => {
 					if let Err(_) = write.try_send(NetworkMessage::Pong(v)) {
@@ -194,6 +228,7 @@ pub fn scan_node(scan_time: Instant, node: SocketAddr, manual: bool) {
 						return future::err(());
 					}
 					check_set_flag!(recvd_pong, "pong");
+					state_lock.rtt = state_lock.ping_sent.map(|sent| sent.elapsed());
 					if let Err(_) = write.try_send(NetworkMessage::GetAddr) {
 						return future::err(());
 					}
@@ -276,11 +311,13 @@ pub fn scan_node(scan_time: Instant, node: SocketAddr, manual: bool) {
 		let printer = unsafe { PRINTER.as_ref().unwrap() };
 		let store = unsafe { DATA_STORE.as_ref().unwrap() };
 		printer.set_stat(Stat::ConnectionClosed);
+		store.close_connection(&node);
 
 		let mut state_lock = final_peer_state.lock().unwrap();
 		if state_lock.recvd_version && state_lock.recvd_verack && state_lock.recvd_pong &&
 				state_lock.recvd_addrs && state_lock.recvd_block {
-			let old_state = store.set_node_state(node, AddressState::Good, state_lock.node_services);
+			let old_state = store.set_node_state(node.clone(), AddressState::Good, state_lock.node_services);
+			store.record_scan_details(&node, state_lock.version.take(), state_lock.rtt, format!("Good {}", &state_lock.msg.0));
 			if manual || (old_state != AddressState::Good && state_lock.msg.0 != "") {
 				printer.add_line(format!("Updating {} from {} to Good {}", node, old_state.to_str(), &state_lock.msg.0), state_lock.msg.1);
 			}
@@ -295,7 +332,13 @@ pub fn scan_node(scan_time: Instant, node: SocketAddr, manual: bool) {
 					state_lock.fail_reason = AddressState::TimeoutAwaitingBlock;
 				}
 			}
-			let old_state = store.set_node_state(node, state_lock.fail_reason, 0);
+			let old_state = store.set_node_state(node.clone(), state_lock.fail_reason, 0);
+			let message = if state_lock.fail_reason == AddressState::TimeoutDuringRequest {
+				format!("Timeout During Request (ver: {}, vack: {})", state_lock.recvd_version, state_lock.recvd_verack)
+			} else {
+				format!("{} {}", state_lock.fail_reason.to_str(), &state_lock.msg.0)
+			};
+			store.record_scan_details(&node, state_lock.version.take(), state_lock.rtt, message);
 			if (manual || old_state != state_lock.fail_reason) && state_lock.fail_reason == AddressState::TimeoutDuringRequest {
 				printer.add_line(format!("Updating {} from {} to Timeout During Request (ver: {}, vack: {})",
 					node, old_state.to_str(), state_lock.recvd_version, state_lock.recvd_verack), true);
@@ -305,23 +348,28 @@ pub fn scan_node(scan_time: Instant, node: SocketAddr, manual: bool) {
 		}
 		future::ok(())
 	}));
+	true
 }
 
 fn poll_dnsseeds(bgp_client: Arc<BGPClient>) {
 	tokio::spawn(future::lazy(|| {
 		let printer = unsafe { PRINTER.as_ref().unwrap() };
 		let store = unsafe { DATA_STORE.as_ref().unwrap() };
+		let network = unsafe { NETWORK.as_ref().unwrap() };
 
 		let mut new_addrs = 0;
-		for seed in ["seed.bitcoin.sipa.be", "dnsseed.bitcoin.dashjr.org", "seed.bitcoinstats.com", "seed.bitcoin.jonasschnelli.ch", "seed.btc.petertodd.org", "seed.bitcoin.sprovoost.nl", "dnsseed.emzy.de"].iter() {
-			new_addrs += store.add_fresh_addrs((*seed, 8333u16).to_socket_addrs().unwrap_or(Vec::new().into_iter()));
-			new_addrs += store.add_fresh_addrs((("x9.".to_string() + seed).as_str(), 8333u16).to_socket_addrs().unwrap_or(Vec::new().into_iter()));
+		for seed in store.get_dns_seeds().iter() {
+			new_addrs += store.add_dns_seed_addrs(seed, (seed.as_str(), network.default_port).to_socket_addrs().unwrap_or(Vec::new().into_iter()).map(SockAddr::from));
+			new_addrs += store.add_dns_seed_addrs(seed, (("x9.".to_string() + seed).as_str(), network.default_port).to_socket_addrs().unwrap_or(Vec::new().into_iter()).map(SockAddr::from));
 		}
 		printer.add_line(format!("Added {} new addresses from other DNS seeds", new_addrs), false);
-		Delay::new(Instant::now() + Duration::from_secs(60)).then(|_| {
-			let store = unsafe { DATA_STORE.as_ref().unwrap() };
+		Delay::new(Instant::now() + Duration::from_secs(60)).then(move |_| {
+			if let Some((for_age, over_cap)) = store.gc_nodes() {
+				printer.add_line(format!("Evicted {} stale nodes and {} nodes over the cap", for_age, over_cap), false);
+			}
 			let dns_future = store.write_dns(Arc::clone(&bgp_client));
-			store.save_data().join(dns_future).then(|_| {
+			let i2p_future = store.write_good_nodes(NetworkType::I2P, "nodes.i2p");
+			store.save_data().join3(dns_future, i2p_future).then(|_| {
 				if !START_SHUTDOWN.load(Ordering::Relaxed) {
 					poll_dnsseeds(bgp_client);
 				} else {
@@ -333,26 +381,40 @@ fn poll_dnsseeds(bgp_client: Arc<BGPClient>) {
 	}));
 }
 
-fn scan_net() {
-	tokio::spawn(future::lazy(|| {
+fn scan_net(bgp_client: Arc<BGPClient>) {
+	tokio::spawn(future::lazy(move || {
 		let printer = unsafe { PRINTER.as_ref().unwrap() };
 		let store = unsafe { DATA_STORE.as_ref().unwrap() };
 
 		let start_time = Instant::now();
-		let mut scan_nodes = store.get_next_scan_nodes();
+		let round_secs = store.get_u64(U64Setting::ScanRoundSecs);
+		let (mut scan_nodes, rate_limited) = datastore::limit_per_subnet_and_asn(store.get_next_scan_nodes(),
+			|ip| bgp_client.get_asn(ip), store.get_u64(U64Setting::MaxSubnetScans), store.get_u64(U64Setting::MaxAsnScans));
 		printer.add_line(format!("Got {} addresses to scan", scan_nodes.len()), false);
+		if !rate_limited.is_empty() {
+			printer.add_line(format!("Deferred {} scans to limit connections to one subnet or ASN", rate_limited.len()), false);
+			store.defer_scans(rate_limited, true);
+		}
 		if !scan_nodes.is_empty() {
-			let per_iter_time = Duration::from_millis(datastore::SECS_PER_SCAN_RESULTS * 1000 / scan_nodes.len() as u64);
+			let per_iter_time = Duration::from_millis(round_secs * 1000 / scan_nodes.len() as u64);
 			let mut iter_time = start_time;
 
+			let mut deferred = Vec::new();
 			for node in scan_nodes.drain(..) {
-				scan_node(iter_time, node, false);
-				iter_time += per_iter_time;
+				if scan_node(iter_time, node.clone(), false) {
+					iter_time += per_iter_time;
+				} else {
+					deferred.push(node);
+				}
+			}
+			if !deferred.is_empty() {
+				printer.add_line(format!("Deferred {} scans as we're at a connection cap", deferred.len()), false);
+				store.defer_scans(deferred, false);
 			}
 		}
-		Delay::new(start_time + Duration::from_secs(datastore::SECS_PER_SCAN_RESULTS)).then(move |_| {
+		Delay::new(start_time + Duration::from_secs(round_secs)).then(move |_| {
 			if !START_SHUTDOWN.load(Ordering::Relaxed) {
-				scan_net();
+				scan_net(bgp_client);
 			}
 			future::ok(())
 		})
@@ -361,7 +423,8 @@ fn scan_net() {
 
 fn make_trusted_conn(trusted_sockaddr: SocketAddr, bgp_client: Arc<BGPClient>) {
 	let printer = unsafe { PRINTER.as_ref().unwrap() };
-	let trusted_peer = Peer::new(trusted_sockaddr.clone(), unsafe { TOR_PROXY.as_ref().unwrap() }, Duration::from_secs(600), printer);
+	let store = unsafe { DATA_STORE.as_ref().unwrap() };
+	let trusted_peer = Peer::new(trusted_sockaddr.into(), unsafe { NETWORK.as_ref().unwrap() }.magic, unsafe { TOR_PROXY.as_ref().unwrap() }, None, Duration::from_secs(600), printer);
 	let bgp_reload = Arc::clone(&bgp_client);
 	tokio::spawn(trusted_peer.and_then(move |(mut trusted_write, trusted_read)| {
 		printer.add_line("Connected to local peer".to_string(), false);
@@ -393,7 +456,7 @@ fn make_trusted_conn(trusted_sockaddr: SocketAddr, bgp_client: Arc<BGPClient>) {
 					}
 				},
 				Some(NetworkMessage::Addr(addrs)) => {
-					unsafe { DATA_STORE.as_ref().unwrap() }.add_fresh_nodes(&addrs);
+					store.add_fresh_nodes(&addrs);
 				},
 				Some(NetworkMessage::Headers(headers)) => {
 					if headers.is_empty() {
@@ -419,7 +482,7 @@ fn make_trusted_conn(trusted_sockaddr: SocketAddr, bgp_client: Arc<BGPClient>) {
 
 						if top_height >= starting_height as u64 {
 							if let Err(_) = trusted_write.try_send(NetworkMessage::GetData(vec![
-									Inventory::WitnessBlock(height_map.get(&(top_height - 216)).unwrap().clone())
+									Inventory::WitnessBlock(height_map.get(&top_height.saturating_sub(store.get_u64(U64Setting::RequestBlockDepth))).unwrap().clone())
 							])) {
 								return future::err(());
 							}
@@ -440,10 +503,10 @@ fn make_trusted_conn(trusted_sockaddr: SocketAddr, bgp_client: Arc<BGPClient>) {
 					let hash = block.block_hash();
 					let header_map = unsafe { HEADER_MAP.as_ref().unwrap() }.lock().unwrap();
 					let height = *header_map.get(&hash).expect("Got loose block from trusted peer we coulnd't have requested");
-					if height == unsafe { HIGHEST_HEADER.as_ref().unwrap() }.lock().unwrap().1 - 216 {
+					if height == unsafe { HIGHEST_HEADER.as_ref().unwrap() }.lock().unwrap().1.saturating_sub(store.get_u64(U64Setting::RequestBlockDepth)) {
 						*unsafe { REQUEST_BLOCK.as_ref().unwrap() }.lock().unwrap() = Arc::new((height, hash, block));
 						if !SCANNING.swap(true, Ordering::SeqCst) {
-							scan_net();
+							scan_net(Arc::clone(&bgp_client));
 							poll_dnsseeds(Arc::clone(&bgp_client));
 						}
 					}
@@ -468,24 +531,168 @@ fn make_trusted_conn(trusted_sockaddr: SocketAddr, bgp_client: Arc<BGPClient>) {
 	}));
 }
 
+fn print_usage() {
+	println!("USAGE: dnsseed-rust datastore localPeerAddress tor_proxy_addr bgp_peer [--dns-bind=addr:53 --dns-name=seed.example.com] [--i2p-sam=addr:7656] [--cjdns] [--srv] [--network=bitcoin|testnet|testnet4|signet|regtest [--signet-challenge=hex]]");
+	println!("       [--zone-origin=seed.example.com --zone-ns=ns1.example.com,... [--zone-ttl=60] [--zone-mbox=hostmaster.example.com] [--zone-soa=refresh,retry,expire,minimum] [--zone-label=@] [--zone-service-prefix=x] [--zone-dnssec]]");
+}
+
 fn main() {
-	if env::args().len() != 5 {
-		println!("USAGE: dnsseed-rust datastore localPeerAddress tor_proxy_addr bgp_peer");
+	if env::args().len() < 5 {
+		print_usage();
+		return;
+	}
+	let mut dns_bind: Option<SocketAddr> = None;
+	let mut dns_name: Option<String> = None;
+	let mut i2p_sam: Option<SocketAddr> = None;
+	let mut cjdns = false;
+	let mut srv = false;
+	let mut zone_dnssec = false;
+	let mut network_name = "bitcoin".to_string();
+	let mut signet_challenge: Option<Vec<u8>> = None;
+	let mut zone_origin: Option<String> = None;
+	let mut zone_ns: Vec<String> = Vec::new();
+	let mut zone_ttl = 60;
+	let mut zone_mbox: Option<String> = None;
+	let mut zone_soa = [3600, 600, 86400, 60];
+	let mut zone_label = "@".to_string();
+	let mut zone_service_prefix = "x".to_string();
+	for arg in env::args().skip(5) {
+		if arg == "--cjdns" {
+			cjdns = true;
+			continue;
+		}
+		if arg == "--srv" {
+			srv = true;
+			continue;
+		}
+		if arg == "--zone-dnssec" {
+			zone_dnssec = true;
+			continue;
+		}
+		match arg.split_once('=') {
+			Some(("--dns-bind", addr)) => match addr.parse() {
+				Ok(addr) => dns_bind = Some(addr),
+				Err(_) => {
+					println!("Invalid --dns-bind address {}", addr);
+					return;
+				},
+			},
+			Some(("--dns-name", name)) => dns_name = Some(name.to_string()),
+			Some(("--i2p-sam", addr)) => match addr.parse() {
+				Ok(addr) => i2p_sam = Some(addr),
+				Err(_) => {
+					println!("Invalid --i2p-sam address {}", addr);
+					return;
+				},
+			},
+			Some(("--network", name)) => network_name = name.to_string(),
+			Some(("--signet-challenge", challenge)) => match Vec::from_hex(challenge) {
+				Ok(challenge) => signet_challenge = Some(challenge),
+				Err(_) => {
+					println!("Invalid --signet-challenge {}, it must be a hex script", challenge);
+					return;
+				},
+			},
+			Some(("--zone-origin", origin)) => zone_origin = Some(origin.to_string()),
+			Some(("--zone-ns", nameservers)) => zone_ns = nameservers.split(',').map(|ns| ns.to_string()).collect(),
+			Some(("--zone-ttl", ttl)) => match ttl.parse() {
+				Ok(ttl) => zone_ttl = ttl,
+				Err(_) => {
+					println!("Invalid --zone-ttl {}, it must be a number of seconds", ttl);
+					print_usage();
+					return;
+				},
+			},
+			Some(("--zone-mbox", mbox)) => zone_mbox = Some(mbox.to_string()),
+			Some(("--zone-soa", timers)) => {
+				let parsed: Result<Vec<u32>, _> = timers.split(',').map(|t| t.parse()).collect();
+				match parsed {
+					Ok(ref parsed) if parsed.len() == zone_soa.len() => zone_soa.copy_from_slice(parsed),
+					_ => {
+						println!("Invalid --zone-soa {}, it must be four numbers of seconds", timers);
+						print_usage();
+						return;
+					},
+				}
+			},
+			Some(("--zone-label", label)) => zone_label = label.to_string(),
+			Some(("--zone-service-prefix", prefix)) => zone_service_prefix = prefix.to_string(),
+			_ => {
+				println!("Unknown option {}", arg);
+				return;
+			},
+		}
+	}
+	if dns_bind.is_some() != dns_name.is_some() {
+		println!("--dns-bind and --dns-name must be given together");
 		return;
 	}
+	// The DNS server answers SOA and NS queries for the seed name with the --zone-* settings too,
+	// naming itself as the nameserver if we weren't given any.
+	let dns_zone = dns_name.as_ref().map(|name| {
+		let nameservers = if zone_ns.is_empty() { vec![name.clone()] } else { zone_ns.clone() };
+		ZoneConfig::new(name, zone_ttl, nameservers, zone_mbox.clone(), zone_soa, "@".to_string(), "x".to_string())
+			.expect("We always have a nameserver")
+	});
+	let mut zone = match zone_origin {
+		Some(origin) => match ZoneConfig::new(&origin, zone_ttl, zone_ns, zone_mbox, zone_soa, zone_label, zone_service_prefix) {
+			Some(zone) => Some(zone),
+			None => {
+				println!("--zone-origin requires at least one --zone-ns");
+				return;
+			},
+		},
+		None => None,
+	};
+	if zone_dnssec {
+		let zone = match zone.as_mut() {
+			Some(zone) => zone,
+			None => {
+				println!("--zone-dnssec requires --zone-origin");
+				return;
+			},
+		};
+		if dns_bind.is_some() {
+			println!("--zone-dnssec can't be used with --dns-bind, as the DNS server doesn't sign its answers");
+			return;
+		}
+		let keys = match DnssecKeys::load_or_generate(&env::args().nth(1).unwrap()) {
+			Ok(keys) => keys,
+			Err(e) => {
+				println!("Failed to load DNSSEC keys: {:?}", e);
+				return;
+			},
+		};
+		// The console is cleared once we start, so also leave the DS record in the store
+		let ds_record = keys.ds_record(zone.origin());
+		println!("Signing zone, publish this DS record in the parent zone: {}", ds_record);
+		if let Err(e) = std::fs::write(env::args().nth(1).unwrap() + "/dnssec_ds", ds_record + "\n") {
+			println!("Failed to write dnssec_ds: {:?}", e);
+			return;
+		}
+		zone.set_dnssec_keys(keys);
+	}
+	match NetworkParams::from_name(&network_name, signet_challenge) {
+		Some(network) => unsafe { NETWORK = Some(Box::new(network)) },
+		None => {
+			println!("Unknown network {} (or --signet-challenge given for a network other than signet)", network_name);
+			return;
+		},
+	}
+	let genesis = &unsafe { NETWORK.as_ref().unwrap() }.genesis;
 
 	unsafe { HEADER_MAP = Some(Box::new(Mutex::new(HashMap::with_capacity(600000)))) };
 	unsafe { HEIGHT_MAP = Some(Box::new(Mutex::new(HashMap::with_capacity(600000)))) };
-	unsafe { HEADER_MAP.as_ref().unwrap() }.lock().unwrap().insert(genesis_block(Network::Bitcoin).block_hash(), 0);
-	unsafe { HEIGHT_MAP.as_ref().unwrap() }.lock().unwrap().insert(0, genesis_block(Network::Bitcoin).block_hash());
-	unsafe { HIGHEST_HEADER = Some(Box::new(Mutex::new((genesis_block(Network::Bitcoin).block_hash(), 0)))) };
-	unsafe { REQUEST_BLOCK = Some(Box::new(Mutex::new(Arc::new((0, genesis_block(Network::Bitcoin).block_hash(), genesis_block(Network::Bitcoin)))))) };
+	unsafe { HEADER_MAP.as_ref().unwrap() }.lock().unwrap().insert(genesis.block_hash(), 0);
+	unsafe { HEIGHT_MAP.as_ref().unwrap() }.lock().unwrap().insert(0, genesis.block_hash());
+	unsafe { HIGHEST_HEADER = Some(Box::new(Mutex::new((genesis.block_hash(), 0)))) };
+	unsafe { REQUEST_BLOCK = Some(Box::new(Mutex::new(Arc::new((0, genesis.block_hash(), genesis.clone()))))) };
 
 	let trt = tokio::runtime::Builder::new()
 		.blocking_threads(2).core_threads(num_cpus::get().max(1) + 1)
 		.build().unwrap();
 
-	let _ = trt.block_on_all(future::lazy(|| {
+	let _ = trt.block_on_all(future::lazy(move || {
 		let mut args = env::args();
 		args.next();
 		let path = args.next().unwrap();
@@ -496,14 +703,27 @@ fn main() {
 
 		let bgp_sockaddr: SocketAddr = args.next().unwrap().parse().unwrap();
 
-		Store::new(path).and_then(move |store| {
+		Store::new(path, unsafe { NETWORK.as_ref().unwrap() }, zone).and_then(move |store| {
 			unsafe { DATA_STORE = Some(Box::new(store)) };
 			let store = unsafe { DATA_STORE.as_ref().unwrap() };
 			unsafe { PRINTER = Some(Box::new(Printer::new(store))) };
+			for warning in store.get_load_warnings() {
+				unsafe { PRINTER.as_ref().unwrap() }.add_line(warning.clone(), true);
+			}
+			store.set_network_reachable(NetworkType::Cjdns, cjdns);
+			store.set_publish_srv(srv);
+
+			if let Some(sam_bridge) = i2p_sam {
+				unsafe { I2P_SESSION = Some(I2PSession::start(sam_bridge, store, PRINTER.as_ref().unwrap())) };
+			}
 
                        let bgp_client = BGPClient::new(bgp_sockaddr, Duration::from_secs(300), unsafe { PRINTER.as_ref().unwrap() });
 			make_trusted_conn(trusted_sockaddr, Arc::clone(&bgp_client));
 
+			if let (Some(bind), Some(dns_zone)) = (dns_bind, dns_zone) {
+				DnsServer::start(bind, dns_zone, store, Arc::clone(&bgp_client), unsafe { PRINTER.as_ref().unwrap() });
+			}
+
 			reader::read(store, unsafe { PRINTER.as_ref().unwrap() }, bgp_client);
 
 			future::ok(())
@@ -512,6 +732,10 @@ fn main() {
 		})
 	}));
 
+	// We failed to load the datastore, and mustn't overwrite it
+	if unsafe { DATA_STORE.is_none() } {
+		std::process::exit(1);
+	}
 	tokio::run(future::lazy(|| {
 		unsafe { DATA_STORE.as_ref().unwrap() }.save_data()
 	}));
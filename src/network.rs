@@ -0,0 +1,107 @@
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::constants::{genesis_block, COIN_VALUE};
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+use bitcoin::consensus::encode::serialize;
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::network::constants::Network;
+
+/// Everything which differs between the chains we can seed for.
+pub struct NetworkParams {
+	/// The message start bytes, as rust-bitcoin represents them (ie read little-endian)
+	pub magic: u32,
+	pub genesis: Block,
+	/// Only nodes on this port are returned in DNS responses, and it's the port we assume nodes
+	/// we learn of from other DNS seeds listen on.
+	pub default_port: u16,
+	pub dns_seeds: &'static [&'static str],
+	/// The label our A/AAAA records are written under in the zone dump
+	pub zone_name: &'static str,
+	/// What Bitcoin Core's contrib/seeds calls the network (eg main, for nodes_main.txt)
+	pub core_seeds_name: &'static str,
+}
+
+/// testnet4 (BIP 94) isn't known to rust-bitcoin, so we build its genesis block by hand.
+fn testnet4_genesis_block() -> Block {
+	let coinbase = Transaction {
+		version: 1,
+		lock_time: 0,
+		input: vec![TxIn {
+			previous_output: OutPoint::null(),
+			script_sig: Builder::new().push_scriptint(486604799).push_scriptint(4)
+				.push_slice(b"03/May/2024 000000000000000000001ebd58c244970b3aa9d783bb001011fbe8ea8e98e00e")
+				.into_script(),
+			sequence: 0xffffffff,
+			witness: vec![],
+		}],
+		output: vec![TxOut {
+			value: 50 * COIN_VALUE,
+			script_pubkey: Builder::new().push_slice(&[0; 33]).push_opcode(opcodes::all::OP_CHECKSIG).into_script(),
+		}],
+	};
+	Block {
+		header: BlockHeader {
+			version: 1,
+			prev_blockhash: Default::default(),
+			merkle_root: coinbase.txid().as_hash().into(),
+			time: 1714777860,
+			bits: 0x1d00ffff,
+			nonce: 393743547,
+		},
+		txdata: vec![coinbase],
+	}
+}
+
+/// Signet magic is the first four bytes of the double-SHA256 of the (length-prefixed) challenge.
+fn signet_magic(challenge: &[u8]) -> u32 {
+	let hash = sha256d::Hash::hash(&serialize(&Script::from(challenge.to_vec())));
+	u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]])
+}
+
+impl NetworkParams {
+	/// Gets the parameters for the named network (bitcoin, testnet, testnet4, signet or regtest).
+	/// A custom signet challenge may only be given for signet, and replaces the default signet's
+	/// magic and upstream seeds (all signets share a genesis block).
+	pub fn from_name(name: &str, signet_challenge: Option<Vec<u8>>) -> Option<NetworkParams> {
+		if signet_challenge.is_some() && name != "signet" { return None; }
+		let (magic, genesis, default_port, dns_seeds, zone_name, core_seeds_name): (_, _, _, &'static [&'static str], _, _) = match name {
+			"bitcoin" => (Network::Bitcoin.magic(), genesis_block(Network::Bitcoin), 8333,
+				&["seed.bitcoin.sipa.be", "dnsseed.bitcoin.dashjr.org", "seed.bitcoinstats.com", "seed.bitcoin.jonasschnelli.ch", "seed.btc.petertodd.org", "seed.bitcoin.sprovoost.nl", "dnsseed.emzy.de"],
+				"seed", "main"),
+			"testnet" => (Network::Testnet.magic(), genesis_block(Network::Testnet), 18333,
+				&["testnet-seed.bitcoin.jonasschnelli.ch", "seed.tbtc.petertodd.org", "seed.testnet.bitcoin.sprovoost.nl", "testnet-seed.bluematt.me"],
+				"seed.testnet", "test"),
+			"testnet4" => (u32::from_le_bytes([0x1c, 0x16, 0x3f, 0x28]), testnet4_genesis_block(), 48333,
+				&["seed.testnet4.bitcoin.sprovoost.nl", "seed.testnet4.wiz.biz"],
+				"seed.testnet4", "testnet4"),
+			"signet" => match signet_challenge {
+				Some(ref challenge) => (signet_magic(challenge), genesis_block(Network::Signet), 38333, &[], "seed.signet", "signet"),
+				None => (Network::Signet.magic(), genesis_block(Network::Signet), 38333,
+					&["seed.signet.bitcoin.sprovoost.nl"], "seed.signet", "signet"),
+			},
+			"regtest" => (Network::Regtest.magic(), genesis_block(Network::Regtest), 18444, &[], "seed.regtest", "regtest"),
+			_ => return None,
+		};
+		Some(NetworkParams { magic, genesis, default_port, dns_seeds, zone_name, core_seeds_name })
+	}
+}
+
+#[test]
+fn test_network_params() {
+	use bitcoin::hashes::hex::FromHex;
+
+	let testnet4 = NetworkParams::from_name("testnet4", None).unwrap();
+	assert_eq!(testnet4.genesis.block_hash().to_string(), "00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043");
+	assert_eq!(testnet4.magic.to_le_bytes(), [0x1c, 0x16, 0x3f, 0x28]);
+
+	// The default signet challenge gives the default signet magic
+	let challenge = Vec::from_hex("512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430210359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae").unwrap();
+	assert_eq!(signet_magic(&challenge), Network::Signet.magic());
+	let custom = NetworkParams::from_name("signet", Some(vec![0x51])).unwrap();
+	assert_ne!(custom.magic, Network::Signet.magic());
+	assert!(custom.dns_seeds.is_empty());
+
+	assert!(NetworkParams::from_name("regtest", Some(vec![0x51])).is_none());
+	assert!(NetworkParams::from_name("litecoin", None).is_none());
+}
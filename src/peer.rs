@@ -1,11 +1,11 @@
 use std::cmp;
-use std::net::{SocketAddr, IpAddr};
+use std::net::SocketAddr;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use bitcoin::consensus::encode;
 use bitcoin::consensus::encode::{Decodable, Encodable};
 use bitcoin::network::address::Address;
-use bitcoin::network::constants::{Network, ServiceFlags};
+use bitcoin::network::constants::ServiceFlags;
 use bitcoin::network::message::{RawNetworkMessage, NetworkMessage};
 use bitcoin::network::message_network::VersionMessage;
 
@@ -19,6 +19,8 @@ use tokio::timer::Delay;
 use futures::sync::mpsc;
 
 use crate::printer::Printer;
+use crate::datastore::SockAddr;
+use crate::i2p::I2PSession;
 
 struct BytesCoder<'a>(&'a mut bytes::BytesMut);
 impl<'a> std::io::Write for BytesCoder<'a> {
@@ -43,7 +45,7 @@ impl<'a> std::io::Read for BytesDecoder<'a> {
 	}
 }
 
-struct MsgCoder<'a>(&'a Printer);
+struct MsgCoder<'a>(&'a Printer, u32);
 impl<'a> codec::Decoder for MsgCoder<'a> {
 	type Item = Option<NetworkMessage>;
 	type Error = encode::Error;
@@ -56,11 +58,11 @@ impl<'a> codec::Decoder for MsgCoder<'a> {
 		match RawNetworkMessage::consensus_decode(&mut decoder) {
 			Ok(res) => {
 				decoder.buf.advance(decoder.pos);
-				if res.magic == Network::Bitcoin.magic() {
+				if res.magic == self.1 {
 					Ok(Some(Some(res.payload)))
 				} else {
 					Err(encode::Error::UnexpectedNetworkMagic {
-						expected: Network::Bitcoin.magic(),
+						expected: self.1,
 						actual: res.magic
 					})
 				}
@@ -81,7 +83,7 @@ impl<'a> codec::Encoder for MsgCoder<'a> {
 
 	fn encode(&mut self, msg: NetworkMessage, res: &mut bytes::BytesMut) -> Result<(), std::io::Error> {
 		if let Err(_) = (RawNetworkMessage {
-			magic: Network::Bitcoin.magic(),
+			magic: self.1,
 			payload: msg,
 		}.consensus_encode(&mut BytesCoder(res))) {
 			//XXX
@@ -90,44 +92,6 @@ impl<'a> codec::Encoder for MsgCoder<'a> {
 	}
 }
 
-// base32 encoder and tests stolen (transliterated) from Bitcoin Core
-// Copyright (c) 2012-2019 The Bitcoin Core developers
-// Distributed under the MIT software license, see
-// http://www.opensource.org/licenses/mit-license.php.
-fn encode_base32(inp: &[u8]) -> String {
-	let mut ret = String::with_capacity(((inp.len() + 4) / 5) * 8);
-
-	let alphabet = "abcdefghijklmnopqrstuvwxyz234567";
-	let mut acc: u16 = 0;
-	let mut bits: u8 = 0;
-	for i in inp {
-		acc = ((acc << 8) | *i as u16) & ((1 << (8 + 5 - 1)) - 1);
-		bits += 8;
-		while bits >= 5 {
-			bits -= 5;
-			let idx = ((acc >> bits) & ((1 << 5) - 1)) as usize;
-			ret += &alphabet[idx..idx + 1];
-		}
-	}
-	if bits != 0 {
-		let idx = ((acc << (5 - bits)) & ((1 << 5) - 1)) as usize;
-		ret += &alphabet[idx..idx + 1];
-	}
-	while ret.len() % 8 != 0 { ret += "=" };
-	return ret;
-}
-
-#[test]
-fn test_encode_base32() {
-	let tests_in = ["","f","fo","foo","foob","fooba","foobar"];
-	let tests_out = ["","my======","mzxq====","mzxw6===","mzxw6yq=","mzxw6ytb","mzxw6ytboi======"];
-	for (inp, out) in tests_in.iter().zip(tests_out.iter()) {
-		assert_eq!(&encode_base32(inp.as_bytes()), out);
-	}
-	// My seednode's onion addr:
-	assert_eq!(&encode_base32(&[0x6a, 0x8b, 0xd2, 0x78, 0x3f, 0x7a, 0xf8, 0x92, 0x8f, 0x80]), "nkf5e6b7pl4jfd4a");
-}
-
 /// Note that this should only be used for really small chunks, ie small enough to *definitely* fit
 /// in the outbound TCP buffer, and shouldn't (practically) block.
 macro_rules! try_write_small {
@@ -141,12 +105,18 @@ macro_rules! try_write_small {
 
 pub struct Peer {}
 impl Peer {
-	pub fn new(addr: SocketAddr, tor_proxy: &SocketAddr, timeout: Duration, printer: &'static Printer) -> impl Future<Error=(), Item=(mpsc::Sender<NetworkMessage>, impl Stream<Item=Option<NetworkMessage>, Error=encode::Error>)> {
+	pub fn new(addr: SockAddr, magic: u32, tor_proxy: &SocketAddr, i2p_session: Option<&'static I2PSession>, timeout: Duration, printer: &'static Printer) -> impl Future<Error=(), Item=(mpsc::Sender<NetworkMessage>, impl Stream<Item=Option<NetworkMessage>, Error=encode::Error>)> {
 		let connect_timeout = Delay::new(Instant::now() + timeout.clone()).then(|_| {
 			future::err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timeout reached"))
 		});
-		match addr.ip() {
-			IpAddr::V6(v6addr) if v6addr.octets()[..6] == [0xFD,0x87,0xD8,0x7E,0xEB,0x43][..] => {
+		let port = addr.port();
+		// Tor v3 onions don't fit in an addr-message address, so just send 0s for them
+		let sockaddr = match addr.ip() {
+			Some(ip) => SocketAddr::new(ip, port),
+			None => "0.0.0.0:0".parse().unwrap(),
+		};
+		match (addr.onion_hostname(), addr.i2p_hostname()) {
+			(Some(hostname), _) => {
 				future::Either::A(connect_timeout.select(TcpStream::connect(&tor_proxy)
 					.and_then(move |mut stream: TcpStream| {
 						try_write_small!(stream, &[5u8, 1u8, 0u8]); // SOCKS5 with 1 method and no auth
@@ -154,13 +124,12 @@ impl Peer {
 							if response != [5, 0] { // SOCKS5 with no auth successful
 								future::Either::B(future::Either::A(future::err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Failed to authenticate"))))
 							} else {
-								let hostname = encode_base32(&v6addr.octets()[6..]) + ".onion";
 								let mut connect_msg = Vec::with_capacity(7 + hostname.len());
 								// SOCKS5 command CONNECT (+ reserved byte) to hostname with given len
 								connect_msg.extend_from_slice(&[5u8, 1u8, 0u8, 3u8, hostname.len() as u8]);
 								connect_msg.extend_from_slice(hostname.as_bytes());
-								connect_msg.push((addr.port() >> 8) as u8);
-								connect_msg.push((addr.port() >> 0) as u8);
+								connect_msg.push((port >> 8) as u8);
+								connect_msg.push((port >> 0) as u8);
 								try_write_small!(stream, &connect_msg);
 								future::Either::B(future::Either::B(read_exact(stream, [0u8; 4]).and_then(move |(stream, response)| {
 									if response[..3] != [5, 0, 0] {
@@ -180,10 +149,18 @@ impl Peer {
 					})
 				).and_then(|(stream, _)| future::ok(stream)).or_else(|(e, _)| future::err(e)))
 			},
-			_ => future::Either::B(connect_timeout.select(TcpStream::connect(&addr))
-				.and_then(|(stream, _)| future::ok(stream)).or_else(|(e, _)| future::err(e))),
+			(None, Some(hostname)) => {
+				let stream_future = match i2p_session {
+					Some(session) => future::Either::A(session.connect(hostname)),
+					None => future::Either::B(future::err(std::io::Error::new(std::io::ErrorKind::NotConnected, "No I2P SAM bridge configured"))),
+				};
+				future::Either::B(future::Either::A(connect_timeout.select(stream_future)
+					.and_then(|(stream, _)| future::ok(stream)).or_else(|(e, _)| future::err(e))))
+			},
+			(None, None) => future::Either::B(future::Either::B(connect_timeout.select(TcpStream::connect(&sockaddr))
+				.and_then(|(stream, _)| future::ok(stream)).or_else(|(e, _)| future::err(e)))),
 		}.and_then(move |stream| {
-				let (write, read) = Framed::new(stream, MsgCoder(printer)).split();
+				let (write, read) = Framed::new(stream, MsgCoder(printer, magic)).split();
 				let (mut sender, receiver) = mpsc::channel(10); // We never really should send more than 10 messages unless they're dumb
 				tokio::spawn(write.sink_map_err(|_| { () }).send_all(receiver)
 					.then(|_| {
@@ -193,7 +170,7 @@ impl Peer {
 					version: 70015,
 					services: ServiceFlags::WITNESS,
 					timestamp: SystemTime::now().duration_since(UNIX_EPOCH).expect("time > 1970").as_secs() as i64,
-					receiver: Address::new(&addr, ServiceFlags::NONE),
+					receiver: Address::new(&sockaddr, ServiceFlags::NONE),
 					sender: Address::new(&"0.0.0.0:0".parse().unwrap(), ServiceFlags::WITNESS),
 					nonce: 0xdeadbeef,
 					user_agent: "/rust-bitcoin:0.18/bluematt-tokio-client:0.1/".to_string(),
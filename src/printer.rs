@@ -3,7 +3,7 @@ use std::collections::LinkedList;
 use std::sync::{Arc, Mutex};
 use std::io::Write;
 
-use crate::datastore::{Store, AddressState, U64Setting, RegexSetting};
+use crate::datastore::{Store, AddressState, NetworkType, U64Setting, RegexSetting};
 
 use crate::START_SHUTDOWN;
 
@@ -64,14 +64,36 @@ impl Printer {
 								store.get_node_count(AddressState::from_num(i).unwrap())
 								).as_bytes()).unwrap();
 					}
+					out.write_all(format!("Nodes due to be scanned: {}\n", store.get_overdue_count()).as_bytes()).unwrap();
+					out.write_all(b"\nNode counts by network (total/good):\n").unwrap();
+					for i in 0..NetworkType::get_count() {
+						let (total, good) = store.get_network_node_count(NetworkType::from_num(i).unwrap());
+						out.write_all(format!("{:22}: {} / {}\n", NetworkType::from_num(i).unwrap().to_str(), total, good).as_bytes()).unwrap();
+					}
+					out.write_all(b"Good node reliability over 2h/8h/1d/7d/30d (reliable count, mean uptime):").unwrap();
+					for (count, uptime) in store.get_reliability_stats().iter() {
+						out.write_all(format!(" {} {:.1}%", count, uptime * 100.0).as_bytes()).unwrap();
+					}
+					out.write_all(b"\n").unwrap();
 					let generations = store.get_bloom_node_count();
 					out.write_all(b"Bloom filter generations contain:").unwrap();
 					for generation in &generations {
 						out.write_all(format!(" {}", generation).as_bytes()).unwrap();
 					}
+					let (evicted_for_age, evicted_over_cap) = store.get_gc_stats();
+					out.write_all(format!("\nNodes evicted since startup: {} stale, {} over the cap", evicted_for_age, evicted_over_cap).as_bytes()).unwrap();
 
 					out.write_all(format!(
 							"\n\nCurrent connections open/in progress: {}\n", stats.connection_count).as_bytes()).unwrap();
+					let conns = store.get_connection_counts();
+					out.write_all(format!(
+							"Connections reserved/cap: {} / {} (IPv4 {} / {}, IPv6 {} / {}, Tor proxy {} / {}), {} scans deferred (\"c all|ipv4|ipv6|tor x\" to change a cap to x, 0 for no limit)\n",
+							conns.total, store.get_u64(U64Setting::MaxConnections), conns.ipv4, store.get_u64(U64Setting::MaxIPv4Connections),
+							conns.ipv6, store.get_u64(U64Setting::MaxIPv6Connections), conns.tor, store.get_u64(U64Setting::MaxTorConnections),
+							conns.deferred).as_bytes()).unwrap();
+					out.write_all(format!(
+							"Connections per round per /24 or /48: {}, per ASN: {}, {} scans deferred (\"c subnet|asn x\" to change to x, 0 for no limit)\n",
+							store.get_u64(U64Setting::MaxSubnetScans), store.get_u64(U64Setting::MaxAsnScans), conns.rate_limited).as_bytes()).unwrap();
 					out.write_all(format!(
 							"Current block count: {}\n", stats.header_count).as_bytes()).unwrap();
 
@@ -84,13 +106,43 @@ impl Printer {
 					out.write_all(format!(
 							"Subversion match regex: {} (\"s x\" to change value to x)\n", store.get_regex(RegexSetting::SubverRegex).as_str()
 							).as_bytes()).unwrap();
+					out.write_all(format!(
+							"Service bits allowed in DNS names: {:x} (\"p x\" to change value to hex x)\n", store.get_u64(U64Setting::AllowedDnsServices)
+							).as_bytes()).unwrap();
+					out.write_all(format!(
+							"Evict nodes not Good or responding for (in seconds): {} (\"g x\" to change to x seconds, 0 for never)\n", store.get_u64(U64Setting::NodeMaxAge)
+							).as_bytes()).unwrap();
+					out.write_all(format!(
+							"Maximum nodes: {} (\"n x\" to change to x, 0 for no limit)\n", store.get_u64(U64Setting::MaxNodes)
+							).as_bytes()).unwrap();
+					out.write_all(format!(
+							"Scan round length (in seconds): {} (\"j x\" to change to x seconds)\n", store.get_u64(U64Setting::ScanRoundSecs)
+							).as_bytes()).unwrap();
+					out.write_all(format!(
+							"Maximum scans per second per status: {} (\"o x\" to change to x)\n", store.get_u64(U64Setting::MaxScansPerSecPerState)
+							).as_bytes()).unwrap();
+					out.write_all(format!(
+							"Requested block depth: {} (\"d x\" to change to x blocks below the tip, at most 288)\n", store.get_u64(U64Setting::RequestBlockDepth)
+							).as_bytes()).unwrap();
+					out.write_all(format!(
+							"Maximum start height above requested block: {} (\"k x\" to change to x blocks)\n", store.get_u64(U64Setting::MaxHeightAhead)
+							).as_bytes()).unwrap();
+
+					out.write_all(format!(
+							"Upstream DNS seeds: {} (\"u x y ...\" to change to x, y, ...)\n", store.get_dns_seeds().join(" ")
+							).as_bytes()).unwrap();
+					out.write_all(b"Upstream DNS seed addresses (returned/new/went good):\n").unwrap();
+					for (seed, seed_stats) in store.get_dns_seed_stats() {
+						out.write_all(format!("{:40}: {} / {} / {}\n", seed, seed_stats.returned, seed_stats.new, seed_stats.good).as_bytes()).unwrap();
+					}
 
-					out.write_all(b"\nRetry times (in seconds):\n").unwrap();
+					out.write_all(b"\nRetry times (in seconds), and the most nodes which keep failing back off to:\n").unwrap();
 					for i in 0..AddressState::get_count() {
 						let scan_secs = store.get_u64(U64Setting::RescanInterval(AddressState::from_num(i).unwrap()));
+						let max_secs = store.get_u64(U64Setting::MaxRescanInterval(AddressState::from_num(i).unwrap()));
 						out.write_all(format!(
-								"{:22} ({:2}): {:5} (ie {} hrs, {} min)\n", AddressState::from_num(i).unwrap().to_str(), i,
-								scan_secs, scan_secs / 60 / 60, (scan_secs / 60) % 60,
+								"{:22} ({:2}): {:5} (ie {} hrs, {} min), at most {} (ie {} hrs, {} min)\n", AddressState::from_num(i).unwrap().to_str(), i,
+								scan_secs, scan_secs / 60 / 60, (scan_secs / 60) % 60, max_secs, max_secs / 60 / 60, (max_secs / 60) % 60,
 								).as_bytes()).unwrap();
 					}
 
@@ -103,11 +155,17 @@ impl Printer {
 					out.write_all(format!(
 							"r x y: Change retry time for status x (int value, see retry times section for name mappings) to y (in seconds)\n"
 							).as_bytes()).unwrap();
+					out.write_all(b"m x y: Change the most nodes in status x which keep failing back off to to y (in seconds)\n").unwrap();
 					out.write_all(format!(
 							"w x: Change the amount of time a node is considered WAS_GOOD after it fails to x from {} (in seconds)\n",
 							store.get_u64(U64Setting::WasGoodTimeout)
 							).as_bytes()).unwrap();
-					out.write_all(b"a x: Scan node x\n").unwrap();
+					out.write_all(b"a x: Scan node x (ip:port or onion:port)\n").unwrap();
+					out.write_all(b"h x: Show scan history and version details for node x\n").unwrap();
+					out.write_all(b"i x: Show everything we know about node x\n").unwrap();
+					out.write_all(b"l f: Add the addresses in file f (one per line)\n").unwrap();
+					out.write_all(b"e f csv|json [state=x] [services=x] [asn=x]: Export nodes (optionally only those in status x, with hex service bits x or in ASN x) to file f\n").unwrap();
+					out.write_all(b"f f [n] [h]: Write Bitcoin Core fixed seeds to file f, with at most n (default 2) per ASN, and as a chainparamsseeds.h to file h\n").unwrap();
 					out.write_all(b"b x: BGP Lookup IP x\n").unwrap();
 					out.write_all(b"\x1b[s").unwrap(); // Save cursor position and provide a blank line before cursor
 					out.write_all(b"\x1b[;H\x1b[2K").unwrap();
@@ -1,68 +1,33 @@
 use std::sync::Arc;
 use std::sync::atomic::Ordering;
 use std::io::BufReader;
-use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::net::IpAddr;
 use std::time::Instant;
 
 use tokio::prelude::*;
 use tokio::io::{stdin, lines};
 
 use crate::printer::Printer;
-use crate::datastore::{Store, AddressState, U64Setting, RegexSetting};
+use crate::datastore::{Store, AddressState, U64Setting, RegexSetting, SockAddr, NodeInfo, unix_time};
 use crate::bgp_client::BGPClient;
+use crate::export::{import_addrs, export_nodes, ExportFilter, ExportFormat};
+use crate::fixed_seeds::write_fixed_seeds;
 
 use crate::{START_SHUTDOWN, scan_node};
 
 use regex::Regex;
 
-// base32 decoder and tests stolen (transliterated) from Bitcoin Core
-// Copyright (c) 2012-2019 The Bitcoin Core developers
-// Distributed under the MIT software license, see
-// http://www.opensource.org/licenses/mit-license.php.
-fn decode_base32(inp: &[u8]) -> Option<Vec<u8>> {
-	let decode32_table: [i8; 256] = [
-		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
-		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
-		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, 26, 27, 28, 29, 30, 31, -1, -1, -1, -1,
-		-1, -1, -1, -1, -1,  0,  1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14,
-		15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, -1, -1, -1, -1, -1, -1,  0,  1,  2,
-		 3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22,
-		23, 24, 25, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
-		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
-		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
-		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
-		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
-		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
-		-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1
-	];
-
-	let mut ret = Vec::with_capacity((inp.len() * 5) / 8);
-
-	let mut acc: u16 = 0;
-	let mut bits: u8 = 0;
-	for i in inp {
-		if *i == '=' as u8 { break; }
-		let codepoint = decode32_table[*i as usize];
-		if codepoint < 0 { return None; }
-		acc = ((acc << 5) | codepoint as u16) & ((1 << (8 + 5 - 1)) - 1);
-		bits += 5;
-		while bits >= 8 {
-			bits -= 8;
-			ret.push((acc >> bits) as u8);
+fn print_history(printer: &Printer, info: &NodeInfo) {
+	let history: Vec<&str> = info.history.iter().map(|state| state.to_str()).collect();
+	printer.add_line(format!("  last scans (newest first): {}", history.join(", ")), false);
+	if let Some(ref details) = info.details {
+		if let Some(ref version) = details.version {
+			printer.add_line(format!("  version at {}: {} (protocol {}, services {:x}, height {})",
+				details.version_time, version.user_agent, version.protocol_version, version.services, version.start_height), false);
 		}
+		let rtt = details.rtt_ms.map(|rtt| format!("{}ms", rtt)).unwrap_or_else(|| "unknown".to_string());
+		printer.add_line(format!("  ping {}, last scan: {}", rtt, details.last_message), false);
 	}
-	Some(ret)
-}
-
-#[test]
-fn test_decode_base32() {
-	let tests_in = ["","f","fo","foo","foob","fooba","foobar"];
-	let tests_out = ["","my======","mzxq====","mzxw6===","mzxw6yq=","mzxw6ytb","mzxw6ytboi======"];
-	for (inp, out) in tests_in.iter().zip(tests_out.iter()) {
-		assert_eq!(&decode_base32(out.as_bytes()).unwrap()[..], inp.as_bytes());
-	}
-	// My seednode's onion addr:
-	assert_eq!(decode_base32("nkf5e6b7pl4jfd4a".as_bytes()).unwrap()[..],[0x6a, 0x8b, 0xd2, 0x78, 0x3f, 0x7a, 0xf8, 0x92, 0x8f, 0x80]);
 }
 
 pub fn read(store: &'static Store, printer: &'static Printer, bgp_client: Arc<BGPClient>) {
@@ -90,10 +55,30 @@ pub fn read(store: &'static Store, printer: &'static Printer, bgp_client: Arc<BG
 				}
 			} }
 		}
+		macro_rules! set_u64 {
+			($setting: expr, $value: expr) => { {
+				if let Err(e) = store.set_u64($setting, $value) {
+					printer.add_line(format!("Invalid setting: {}", e), true);
+				}
+			} }
+		}
 		match get_next_chunk!() {
-			"t" => store.set_u64(U64Setting::RunTimeout, try_parse_next_chunk!(u64)),
-			"v" => store.set_u64(U64Setting::MinProtocolVersion, try_parse_next_chunk!(u64)),
-			"w" => store.set_u64(U64Setting::WasGoodTimeout, try_parse_next_chunk!(u64)),
+			"t" => set_u64!(U64Setting::RunTimeout, try_parse_next_chunk!(u64)),
+			"v" => set_u64!(U64Setting::MinProtocolVersion, try_parse_next_chunk!(u64)),
+			"w" => set_u64!(U64Setting::WasGoodTimeout, try_parse_next_chunk!(u64)),
+			"g" => set_u64!(U64Setting::NodeMaxAge, try_parse_next_chunk!(u64)),
+			"n" => set_u64!(U64Setting::MaxNodes, try_parse_next_chunk!(u64)),
+			"j" => set_u64!(U64Setting::ScanRoundSecs, try_parse_next_chunk!(u64)),
+			"o" => set_u64!(U64Setting::MaxScansPerSecPerState, try_parse_next_chunk!(u64)),
+			"d" => set_u64!(U64Setting::RequestBlockDepth, try_parse_next_chunk!(u64)),
+			"k" => set_u64!(U64Setting::MaxHeightAhead, try_parse_next_chunk!(u64)),
+			"p" => {
+				match u64::from_str_radix(get_next_chunk!(), 16) {
+					Ok(services) => set_u64!(U64Setting::AllowedDnsServices, services),
+					Err(_) => err!(),
+				}
+			},
+			"u" => store.set_dns_seeds(line_iter.filter(|s| !s.is_empty()).map(|s| s.to_string()).collect()),
 			"s" => {
 				if line.len() < 3 || !line.starts_with("s ") {
 					err!();
@@ -104,21 +89,99 @@ pub fn read(store: &'static Store, printer: &'static Printer, bgp_client: Arc<BG
 				});
 			},
 			"a" => {
-				let host_port = get_next_chunk!();
-				let parsed = if host_port.len() > 23 && &host_port[16..23] == ".onion:" {
-					let port = match host_port[23..].parse::<u16>() { Ok(res) => res, Err(_) => err!(), };
-
-					let ipv6 = match decode_base32(host_port[0..16].as_bytes()) { Some(res) => res, None => err!(), };
-					if ipv6.len() != 10 { err!(); }
-					let mut octets = [0xFD,0x87,0xD8,0x7E,0xEB,0x43, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-					octets[6..].copy_from_slice(&ipv6[0..10]);
-
-					SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)
-				} else {
-					match host_port.parse::<SocketAddr>() {
-						Ok(res) => res, Err(_) => err!(), }
+				let parsed = try_parse_next_chunk!(SockAddr);
+				scan_node(Instant::now(), parsed, true);
+			},
+			"h" => {
+				let addr = try_parse_next_chunk!(SockAddr);
+				match store.get_node_info(&addr).0 {
+					Some(info) => {
+						printer.add_line(format!("{} is {}", addr, info.state.to_str()), false);
+						print_history(printer, &info);
+					},
+					None => printer.add_line(format!("{} is not a known node", addr), false),
+				}
+			},
+			"l" => {
+				let path = get_next_chunk!();
+				match import_addrs(store, path) {
+					Ok((count, new, bad_lines)) => {
+						printer.add_line(format!("Read {} addresses from {}, {} of which were new", count, path, new), false);
+						if !bad_lines.is_empty() {
+							printer.add_line(format!("Couldn't parse lines {:?} of {}", bad_lines, path), true);
+						}
+					},
+					Err(e) => printer.add_line(format!("Failed to read {}: {:?}", path, e), true),
+				}
+			},
+			"e" => {
+				let path = get_next_chunk!();
+				let format = match get_next_chunk!() {
+					"csv" => ExportFormat::Csv,
+					"json" => ExportFormat::Json,
+					_ => err!(),
+				};
+				let mut filter = ExportFilter::default();
+				for arg in line_iter {
+					match arg.split_once('=') {
+						Some(("state", state)) => match state.parse().ok().and_then(AddressState::from_num) {
+							Some(state) => filter.state = Some(state),
+							None => err!(),
+						},
+						Some(("services", services)) => match u64::from_str_radix(services, 16) {
+							Ok(services) => filter.services = services,
+							Err(_) => err!(),
+						},
+						Some(("asn", asn)) => match asn.parse() {
+							Ok(asn) => filter.asn = Some(asn),
+							Err(_) => err!(),
+						},
+						_ => err!(),
+					}
+				}
+				match export_nodes(store, &bgp_client, path, &filter, format) {
+					Ok(count) => printer.add_line(format!("Exported {} nodes to {}", count, path), false),
+					Err(e) => printer.add_line(format!("Failed to write {}: {:?}", path, e), true),
+				}
+			},
+			"f" => {
+				let nodes_path = get_next_chunk!();
+				let max_per_asn = match line_iter.next() {
+					Some(max) => match max.parse() {
+						Ok(max) => max,
+						Err(_) => err!(),
+					},
+					None => 2,
 				};
-				scan_node(Instant::now(), parsed, true)
+				let header_path = line_iter.next();
+				match write_fixed_seeds(store, &bgp_client, nodes_path, header_path, max_per_asn) {
+					Ok(count) => printer.add_line(format!("Wrote {} fixed seeds to {}", count, nodes_path), false),
+					Err(e) => printer.add_line(format!("Failed to write fixed seeds: {:?}", e), true),
+				}
+			},
+			"i" => {
+				let addr = try_parse_next_chunk!(SockAddr);
+				let (info, in_timeout_filter) = store.get_node_info(&addr);
+				match info {
+					Some(info) => {
+						printer.add_line(format!("{} is {} with services {:x} (as of when it was last Good)", addr, info.state.to_str(), info.services), false);
+						let now = unix_time();
+						let time = |t: u32| if t == 0 { "never".to_string() } else { format!("{} ({}s ago)", t, now.saturating_sub(t)) };
+						printer.add_line(format!("  first seen {}, last good {}, last seen {}, last scanned {}, {} failed scans in a row",
+							time(info.first_seen), time(info.last_good), time(info.last_seen), time(info.last_attempt), info.failures), false);
+						match store.get_next_scan(&addr) {
+							Some((due, earlier)) => printer.add_line(format!("  next scan due {} (in {}s), after about {} other nodes in its state", due, (due as i64) - (now as i64), earlier), false),
+							None => printer.add_line("  being scanned now".to_string(), false),
+						}
+						print_history(printer, &info);
+					},
+					None => printer.add_line(format!("{} is not a known node", addr), false),
+				}
+				printer.add_line(format!("  {} the timeout filter", if in_timeout_filter { "in" } else { "not in" }), false);
+				if let Some(ip) = addr.ip() {
+					let (prefixlen, path) = bgp_client.get_path(ip);
+					printer.add_line(format!("  ASN {} (prefixlen {}, path: {:?})", bgp_client.get_asn(ip), prefixlen, path), false);
+				}
 			},
 			"b" => {
 				let ip = try_parse_next_chunk!(IpAddr);
@@ -126,7 +189,25 @@ pub fn read(store: &'static Store, printer: &'static Printer, bgp_client: Arc<BG
 			},
 			"r" => {
 				match AddressState::from_num(try_parse_next_chunk!(u8)) {
-					Some(state) => store.set_u64(U64Setting::RescanInterval(state), try_parse_next_chunk!(u64)),
+					Some(state) => set_u64!(U64Setting::RescanInterval(state), try_parse_next_chunk!(u64)),
+					None => err!(),
+				}
+			},
+			"c" => {
+				let setting = match get_next_chunk!() {
+					"all" => U64Setting::MaxConnections,
+					"ipv4" => U64Setting::MaxIPv4Connections,
+					"ipv6" => U64Setting::MaxIPv6Connections,
+					"tor" => U64Setting::MaxTorConnections,
+					"subnet" => U64Setting::MaxSubnetScans,
+					"asn" => U64Setting::MaxAsnScans,
+					_ => err!(),
+				};
+				set_u64!(setting, try_parse_next_chunk!(u64))
+			},
+			"m" => {
+				match AddressState::from_num(try_parse_next_chunk!(u8)) {
+					Some(state) => set_u64!(U64Setting::MaxRescanInterval(state), try_parse_next_chunk!(u64)),
 					None => err!(),
 				}
 			},
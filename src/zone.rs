@@ -0,0 +1,285 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dnssec::DnssecKeys;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_NS: u16 = 2;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_RRSIG: u16 = 46;
+pub const TYPE_NSEC: u16 = 47;
+pub const TYPE_DNSKEY: u16 = 48;
+
+pub fn type_to_str(rtype: u16) -> String {
+	match rtype {
+		TYPE_A => "A".to_string(),
+		TYPE_NS => "NS".to_string(),
+		TYPE_SOA => "SOA".to_string(),
+		TYPE_AAAA => "AAAA".to_string(),
+		TYPE_SRV => "SRV".to_string(),
+		TYPE_RRSIG => "RRSIG".to_string(),
+		TYPE_NSEC => "NSEC".to_string(),
+		TYPE_DNSKEY => "DNSKEY".to_string(),
+		_ => format!("TYPE{}", rtype), // RFC 3597
+	}
+}
+
+/// Appends the root label to a name if it isn't already fully-qualified.
+fn fqdn(name: &str) -> String {
+	if name.ends_with('.') { name.to_string() } else { name.to_string() + "." }
+}
+
+/// Encodes a fully-qualified name in (uncompressed, lowercased) wire format.
+pub fn name_to_wire(name: &str) -> Vec<u8> {
+	let mut res = Vec::with_capacity(name.len() + 1);
+	for label in name.trim_end_matches('.').split('.').filter(|l| !l.is_empty()) {
+		res.push(label.len() as u8);
+		res.extend_from_slice(label.to_ascii_lowercase().as_bytes());
+	}
+	res.push(0);
+	res
+}
+
+/// Gets the label we publish a node's address under when it's an SRV target: "n", then the IP's
+/// octets and the port in hex, so that (unlike with A/AAAA records) the port is preserved.
+pub fn srv_target_label(addr: &SocketAddr) -> String {
+	let octets = match addr.ip() {
+		IpAddr::V4(v4) => v4.octets().to_vec(),
+		IpAddr::V6(v6) => v6.octets().to_vec(),
+	};
+	let mut res = "n".to_string();
+	for b in octets.iter().chain(addr.port().to_be_bytes().iter()) {
+		res += &format!("{:02x}", b);
+	}
+	res
+}
+
+/// The inverse of srv_target_label (which must be lowercase), returning None for any other label.
+pub fn parse_srv_target_label(label: &str) -> Option<SocketAddr> {
+	if !label.starts_with('n') || (label.len() != 13 && label.len() != 37) { return None; }
+	if !label[1..].bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f')) { return None; }
+	let bytes: Vec<u8> = (1..label.len()).step_by(2).map(|i| u8::from_str_radix(&label[i..i + 2], 16).unwrap()).collect();
+	let port = u16::from_be_bytes([bytes[bytes.len() - 2], bytes[bytes.len() - 1]]);
+	let ip = if bytes.len() == 6 {
+		IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))
+	} else {
+		let mut octets = [0; 16];
+		octets.copy_from_slice(&bytes[..16]);
+		IpAddr::V6(Ipv6Addr::from(octets))
+	};
+	Some(SocketAddr::new(ip, port))
+}
+
+/// Gets the RDATA of an SRV record with zero priority and weight, given the target's name in
+/// wire format.
+pub fn srv_rdata(port: u16, target_wire: &[u8]) -> Vec<u8> {
+	let mut res = vec![0, 0, 0, 0];
+	res.extend_from_slice(&port.to_be_bytes());
+	res.extend_from_slice(target_wire);
+	res
+}
+
+/// The data of a record in the seed's part of the zone, with any names relative to the origin.
+pub enum RecordData {
+	Addr(IpAddr),
+	/// An SRV record pointing at port on the given target label
+	Srv(u16, String),
+}
+
+impl RecordData {
+	/// Gets the record type and presentation format RDATA (with names left relative).
+	pub fn type_text(&self) -> (u16, String) {
+		match self {
+			RecordData::Addr(IpAddr::V4(v4)) => (TYPE_A, v4.to_string()),
+			RecordData::Addr(IpAddr::V6(v6)) => (TYPE_AAAA, v6.to_string()),
+			RecordData::Srv(port, target) => (TYPE_SRV, format!("0 0 {} {}", port, target)),
+		}
+	}
+}
+
+/// A single resource record, with its RDATA in both wire and presentation format.
+pub struct Record {
+	/// Fully-qualified and lowercase
+	pub name: String,
+	pub rtype: u16,
+	pub ttl: u32,
+	pub rdata: Vec<u8>,
+	pub text: String,
+}
+
+impl Record {
+	fn name_rdata(name: &str, rtype: u16, ttl: u32, target: &str) -> Record {
+		Record { name: name.to_string(), rtype, ttl, rdata: name_to_wire(target), text: target.to_string() }
+	}
+}
+
+/// Everything needed to write a complete, loadable zone rather than bare records.
+pub struct ZoneConfig {
+	origin: String,
+	ttl: u32,
+	nameservers: Vec<String>,
+	mbox: String,
+	/// SOA refresh, retry, expire and minimum (negative-caching TTL), in seconds
+	soa_timers: [u32; 4],
+	/// The label (relative to origin, or "@" for origin itself) the seed's records live under
+	seed_label: String,
+	/// The prefix of the labels for service bit subdomains, ie "x" gives x9.<seed_label>
+	service_prefix: String,
+	last_serial: AtomicU32,
+	dnssec_keys: Option<DnssecKeys>,
+}
+
+impl ZoneConfig {
+	/// Builds a zone config from the --zone-* options. The first nameserver is used as the SOA
+	/// MNAME, and mbox defaults to hostmaster at the origin.
+	pub fn new(origin: &str, ttl: u32, nameservers: Vec<String>, mbox: Option<String>, soa_timers: [u32; 4], seed_label: String, service_prefix: String) -> Option<ZoneConfig> {
+		if nameservers.is_empty() { return None; }
+		let origin = fqdn(origin).to_ascii_lowercase();
+		Some(ZoneConfig {
+			mbox: fqdn(&mbox.unwrap_or_else(|| "hostmaster.".to_string() + &origin)),
+			origin,
+			ttl,
+			nameservers: nameservers.iter().map(|ns| fqdn(ns)).collect(),
+			soa_timers,
+			seed_label,
+			service_prefix,
+			last_serial: AtomicU32::new(0),
+			dnssec_keys: None,
+		})
+	}
+
+	/// Signs the zone with the given keys whenever it is rendered.
+	pub fn set_dnssec_keys(&mut self, keys: DnssecKeys) {
+		self.dnssec_keys = Some(keys);
+	}
+
+	pub fn origin(&self) -> &str {
+		&self.origin
+	}
+
+	/// Gets a new SOA serial, which is the current unix time unless we've already written a zone
+	/// in this second (or the clock went backwards), in which case it's one more than the last.
+	fn next_serial(&self) -> u32 {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("time > 1970").as_secs() as u32;
+		let mut last = self.last_serial.load(Ordering::Acquire);
+		loop {
+			let serial = std::cmp::max(now, last.wrapping_add(1));
+			match self.last_serial.compare_exchange(last, serial, Ordering::AcqRel, Ordering::Acquire) {
+				Ok(_) => return serial,
+				Err(cur) => last = cur,
+			}
+		}
+	}
+
+	/// Gets the SOA (with the given serial) and NS records at the zone apex.
+	pub fn apex_records(&self, serial: u32) -> Vec<Record> {
+		let mut soa_rdata = name_to_wire(&self.nameservers[0]);
+		soa_rdata.extend_from_slice(&name_to_wire(&self.mbox));
+		soa_rdata.extend_from_slice(&serial.to_be_bytes());
+		for timer in self.soa_timers.iter() {
+			soa_rdata.extend_from_slice(&timer.to_be_bytes());
+		}
+		let mut res = vec![Record {
+			name: self.origin.clone(),
+			rtype: TYPE_SOA,
+			ttl: self.ttl,
+			rdata: soa_rdata,
+			text: format!("{} {} {} {} {} {} {}", self.nameservers[0], self.mbox, serial,
+				self.soa_timers[0], self.soa_timers[1], self.soa_timers[2], self.soa_timers[3]),
+		}];
+		for ns in self.nameservers.iter() {
+			res.push(Record::name_rdata(&self.origin, TYPE_NS, self.ttl, ns));
+		}
+		res
+	}
+
+	/// Gets the fully-qualified name for a label relative to the origin (or "@").
+	fn label_to_name(&self, label: &str) -> String {
+		if label == "@" { self.origin.clone() } else { format!("{}.{}", label.to_ascii_lowercase(), self.origin) }
+	}
+
+	/// Renders the complete zone given the A/AAAA/SRV records (as labels relative to the origin,
+	/// or "@") to include, signing it if we have DNSSEC keys.
+	pub fn render(&self, entries: &[(String, RecordData)]) -> String {
+		let mut records = self.apex_records(self.next_serial());
+		for (label, data) in entries.iter() {
+			let rdata = match data {
+				RecordData::Addr(IpAddr::V4(v4)) => v4.octets().to_vec(),
+				RecordData::Addr(IpAddr::V6(v6)) => v6.octets().to_vec(),
+				RecordData::Srv(port, target) => srv_rdata(*port, &name_to_wire(&self.label_to_name(target))),
+			};
+			let (rtype, text) = data.type_text();
+			records.push(Record { name: self.label_to_name(label), rtype, ttl: self.ttl, rdata, text });
+		}
+		if let Some(ref keys) = self.dnssec_keys {
+			let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("time > 1970").as_secs() as u32;
+			records = keys.sign_zone(&self.origin, records, self.ttl, self.soa_timers[3], now);
+		}
+
+		let mut res = format!("$ORIGIN {}\n$TTL {}\n", self.origin, self.ttl);
+		for record in records {
+			let label = if record.name == self.origin { "@" } else { record.name.trim_end_matches(&format!(".{}", self.origin)) };
+			if record.ttl == self.ttl {
+				res += &format!("{}\tIN\t{}\t{}\n", label, type_to_str(record.rtype), record.text);
+			} else {
+				res += &format!("{}\t{}\tIN\t{}\t{}\n", label, record.ttl, type_to_str(record.rtype), record.text);
+			}
+		}
+		res
+	}
+
+	pub fn seed_label(&self) -> &str {
+		&self.seed_label
+	}
+
+	pub fn service_prefix(&self) -> &str {
+		&self.service_prefix
+	}
+}
+
+/// Gets the label for a subdomain of the given (relative) label, which may be "@".
+pub fn sub_label(sub: &str, label: &str) -> String {
+	if label == "@" { sub.to_string() } else { format!("{}.{}", sub, label) }
+}
+
+#[test]
+fn test_zone_render() {
+	let zone = ZoneConfig::new("Seed.Example.com", 60, vec!["ns1.example.com".to_string(), "ns2.example.com.".to_string()],
+		None, [3600, 600, 86400, 60], "@".to_string(), "x".to_string()).unwrap();
+	let rendered = zone.render(&[("@".to_string(), RecordData::Addr("1.2.3.4".parse().unwrap())),
+		("x9".to_string(), RecordData::Addr("2001:db8::1".parse().unwrap())),
+		("_bitcoin._tcp".to_string(), RecordData::Srv(8334, "n01020304208e".to_string()))]);
+	let lines: Vec<&str> = rendered.lines().collect();
+	assert_eq!(lines[0], "$ORIGIN seed.example.com.");
+	assert_eq!(lines[1], "$TTL 60");
+	let soa: Vec<&str> = lines[2].split(['\t', ' ']).collect();
+	assert_eq!(&soa[..5], &["@", "IN", "SOA", "ns1.example.com.", "hostmaster.seed.example.com."]);
+	assert_eq!(&soa[6..], &["3600", "600", "86400", "60"]);
+	assert_eq!(&lines[3..], &["@\tIN\tNS\tns1.example.com.", "@\tIN\tNS\tns2.example.com.",
+		"@\tIN\tA\t1.2.3.4", "x9\tIN\tAAAA\t2001:db8::1", "_bitcoin._tcp\tIN\tSRV\t0 0 8334 n01020304208e"]);
+
+	// Serials always go up, even within a second
+	let serial: u32 = soa[5].parse().unwrap();
+	assert!(zone.next_serial() > serial);
+	assert!(zone.next_serial() > serial + 1);
+
+	assert_eq!(sub_label("x9", "@"), "x9");
+	assert_eq!(sub_label("x9", "seed"), "x9.seed");
+	assert_eq!(name_to_wire("Seed.example.com."), b"\x04seed\x07example\x03com\x00");
+
+	let v4: SocketAddr = "1.2.3.4:8334".parse().unwrap();
+	assert_eq!(srv_target_label(&v4), "n01020304208e");
+	assert_eq!(parse_srv_target_label("n01020304208e"), Some(v4));
+	let v6: SocketAddr = "[2001:db8::1]:18333".parse().unwrap();
+	assert_eq!(srv_target_label(&v6), "n20010db8000000000000000000000001479d");
+	assert_eq!(parse_srv_target_label(&srv_target_label(&v6)), Some(v6));
+	for label in ["n01020304208", "n01020304208E", "x01020304208e", "n0102030420+e", "n0102030420\u{e9}"].iter() {
+		assert_eq!(parse_srv_target_label(label), None);
+	}
+	assert_eq!(srv_rdata(8333, &name_to_wire("a.b")), b"\x00\x00\x00\x00\x20\x8d\x01a\x01b\x00");
+
+	assert!(ZoneConfig::new("seed.example.com", 60, Vec::new(), None, [0; 4], "@".to_string(), "x".to_string()).is_none());
+}